@@ -0,0 +1,682 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::Hasher;
+use std::ops::Range;
+use std::slice;
+
+use lexer::{Token, TokenKind, Tokenizer};
+use serializer::display_stream;
+use transform;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackError {
+    /// The token at this index needs more values than are available.
+    Underflow(usize),
+}
+
+/// Peak depth reached by the ESIL stack while evaluating `tokens`.
+///
+/// Every conditional block is considered both taken and skipped; the deeper
+/// of the two stacks is carried on past the closing `}`.
+pub fn max_stack_depth(tokens: &[Token]) -> Result<usize, StackError> {
+    let mut stack = StackState::default();
+    for (i, token) in tokens.iter().enumerate() {
+        stack.step(i, token)?;
+    }
+    Ok(stack.peak)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckError {
+    /// The token at this index needs more values than are available.
+    Underflow(usize),
+    /// This many values are left on the stack at the end.
+    Unbalanced(usize),
+}
+
+/// Tokenize `esil` with the default options, checking as it goes that the
+/// stack never underflows and is empty at the end. Stops at the first
+/// underflowing token.
+pub fn tokenize_checked<T: AsRef<str>>(esil: T) -> Result<Vec<Token>, CheckError> {
+    let tokenizer = Tokenizer::new();
+    let mut stack = StackState::default();
+    let mut tokens = Vec::new();
+    for token in tokenizer.expand_operators(esil.as_ref().trim().split(',')) {
+        stack.step(tokens.len(), &token)
+             .map_err(|StackError::Underflow(i)| CheckError::Underflow(i))?;
+        tokens.push(token);
+    }
+    match stack.depth {
+        0 => Ok(tokens),
+        depth => Err(CheckError::Unbalanced(depth)),
+    }
+}
+
+// Stack depths tracked by `max_stack_depth` and `tokenize_checked`.
+#[derive(Default)]
+struct StackState {
+    depth: usize,
+    peak: usize,
+    // Values held on the parser's temporary stack by `PCopy`.
+    temp: usize,
+    // Stack state at the start of each open conditional block.
+    branches: Vec<(usize, usize)>,
+}
+
+impl StackState {
+    fn step(&mut self, i: usize, token: &Token) -> Result<(), StackError> {
+        let (pop, push) = token.stack_effect();
+        if self.depth < pop {
+            return Err(StackError::Underflow(i));
+        }
+        self.depth = self.depth - pop + push;
+        match *token {
+            Token::EIf => self.branches.push((self.depth, self.temp)),
+            Token::ENop => {
+                if let Some((d, t)) = self.branches.pop() {
+                    self.depth = cmp::max(self.depth, d);
+                    self.temp = cmp::max(self.temp, t);
+                }
+            }
+            Token::EClear => self.depth = 0,
+            Token::PCopy(n) => self.temp += n,
+            Token::PPop(n) => {
+                if self.temp < n {
+                    return Err(StackError::Underflow(i));
+                }
+                self.temp -= n;
+            }
+            _ => { }
+        }
+        self.peak = cmp::max(self.peak, self.depth);
+        Ok(())
+    }
+}
+
+/// Address of the instruction the token at `pos` came from, given the
+/// `(address, first token)` index built by `Tokenizer::tokenize_append`.
+/// Useful to point a `StackError` back at the offending instruction.
+pub fn instruction_address(index: &[(u64, usize)], pos: usize) -> Option<u64> {
+    index.iter()
+         .take_while(|&&(_, start)| start <= pos)
+         .last()
+         .map(|&(addr, _)| addr)
+}
+
+/// A memory access found by `effects`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryAccess {
+    /// Address of the access, when it is a constant.
+    pub addr: Option<u64>,
+    /// Width of the access in bits, 0 for a word.
+    pub width: u16,
+}
+
+/// Registers and memory touched by an instruction's ESIL. Registers are kept
+/// sorted so the output is reproducible.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstructionEffects {
+    pub reads: BTreeSet<String>,
+    pub writes: BTreeSet<String>,
+    pub mem_reads: Vec<MemoryAccess>,
+    pub mem_writes: Vec<MemoryAccess>,
+}
+
+impl InstructionEffects {
+    /// Memory writes at a constant address overlapping `range`, eg. the code
+    /// section to find self-modifying code. Writes to computed addresses
+    /// are not known statically and never returned. Word-sized writes are
+    /// assumed to be 8 bytes wide, so none is missed on any target.
+    pub fn writes_to_range(&self, range: Range<u64>) -> Vec<&MemoryAccess> {
+        self.mem_writes
+            .iter()
+            .filter(|access| match access.addr {
+                Some(addr) => {
+                    let bytes = match access.width {
+                        0 => 8,
+                        bits => cmp::max(bits / 8, 1),
+                    };
+                    let end = addr.saturating_add(u64::from(bytes));
+                    addr < range.end && range.start < end
+                }
+                None => false,
+            })
+            .collect()
+    }
+}
+
+// Value on the symbolic stack used by `effects`.
+#[derive(Clone)]
+enum Operand {
+    Register(String),
+    Constant(u64),
+    Computed,
+}
+
+impl Operand {
+    fn constant(&self) -> Option<u64> {
+        match *self {
+            Operand::Constant(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Statically collect the registers and memory read and written by
+/// `tokens`, without evaluating them.
+///
+/// An identifier counts as read when an operation consumes it and as
+/// written when it is the destination of `=`. Conditional blocks are
+/// treated as taken.
+pub fn effects(tokens: &[Token]) -> InstructionEffects {
+    let mut reads = BTreeSet::new();
+    let mut writes = BTreeSet::new();
+    let mut mem_reads = Vec::new();
+    let mut mem_writes = Vec::new();
+    let mut stack: Vec<Operand> = Vec::new();
+    let mut temp: Vec<Operand> = Vec::new();
+    let mut read = |operand: Option<Operand>| {
+        if let Some(Operand::Register(name)) = operand {
+            reads.insert(name);
+        }
+    };
+    for token in tokens {
+        match *token {
+            Token::EIdentifier(ref name) => stack.push(Operand::Register(name.clone())),
+            Token::EConstant(v) => stack.push(Operand::Constant(v)),
+            Token::EEq | Token::EWeakEq => {
+                if let Some(Operand::Register(name)) = stack.pop() {
+                    writes.insert(name);
+                }
+                read(stack.pop());
+            }
+            Token::EPeek(width) => {
+                let addr = stack.pop();
+                mem_reads.push(MemoryAccess {
+                    addr: addr.as_ref().and_then(Operand::constant),
+                    width,
+                });
+                read(addr);
+                stack.push(Operand::Computed);
+            }
+            Token::EPoke(width) => {
+                let addr = stack.pop();
+                mem_writes.push(MemoryAccess {
+                    addr: addr.as_ref().and_then(Operand::constant),
+                    width,
+                });
+                read(addr);
+                read(stack.pop());
+            }
+            Token::EDup => {
+                if let Some(top) = stack.last().cloned() {
+                    stack.push(top);
+                }
+            }
+            Token::EClear => stack.clear(),
+            Token::PCopy(n) => {
+                let from = stack.len().saturating_sub(n);
+                temp.extend_from_slice(&stack[from..]);
+            }
+            Token::PPop(n) => {
+                let from = temp.len().saturating_sub(n);
+                stack.extend(temp.drain(from..));
+            }
+            _ => {
+                let (pop, push) = token.stack_effect();
+                for _ in 0..pop {
+                    read(stack.pop());
+                }
+                for _ in 0..push {
+                    stack.push(Operand::Computed);
+                }
+            }
+        }
+    }
+    InstructionEffects {
+        reads,
+        writes,
+        mem_reads,
+        mem_writes,
+    }
+}
+
+/// Names of all identifiers in `tokens`, sorted.
+pub fn referenced_registers(tokens: &[Token]) -> BTreeSet<String> {
+    tokens.iter()
+          .filter_map(|t| match *t {
+              Token::EIdentifier(ref name) => Some(name.clone()),
+              _ => None,
+          })
+          .collect()
+}
+
+/// Flags a target provides, for `validate_flags`. `$r` and `$$` are always
+/// available.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlagSet {
+    pub zero: bool,
+    pub carry: bool,
+    pub parity: bool,
+    pub overflow: bool,
+    pub sign: bool,
+    pub borrow: bool,
+}
+
+impl FlagSet {
+    /// Every flag, as on x86.
+    pub fn all() -> FlagSet {
+        FlagSet {
+            zero: true,
+            carry: true,
+            parity: true,
+            overflow: true,
+            sign: true,
+            borrow: true,
+        }
+    }
+
+    /// The flags of an ARM-like target, which has no parity flag.
+    pub fn arm() -> FlagSet {
+        FlagSet { parity: false, ..FlagSet::all() }
+    }
+
+    // Whether `token` is available, which anything but a flag is.
+    fn has(&self, token: &Token) -> bool {
+        match *token {
+            Token::IZero(_) => self.zero,
+            Token::ICarry(_) => self.carry,
+            Token::IParity(_) => self.parity,
+            Token::IOverflow(_) => self.overflow,
+            Token::ISign(_) => self.sign,
+            Token::IBorrow(_) => self.borrow,
+            _ => true,
+        }
+    }
+}
+
+impl Default for FlagSet {
+    fn default() -> FlagSet {
+        FlagSet::all()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlagError {
+    /// The internal var at this index is a flag the target does not have.
+    Unsupported(usize),
+}
+
+/// Check that every internal var in `tokens` is a flag `target` has.
+pub fn validate_flags(tokens: &[Token], target: &FlagSet) -> Result<(), Vec<FlagError>> {
+    let errors: Vec<_> = tokens.iter()
+                               .enumerate()
+                               .filter(|&(_, token)| !target.has(token))
+                               .map(|(i, _)| FlagError::Unsupported(i))
+                               .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Whether any token in the stream reads or writes memory.
+pub fn contains_memory_access(tokens: &[Token]) -> bool {
+    tokens.iter().any(|t| t.kind() == TokenKind::Memory)
+}
+
+/// Partition `tokens` into basic blocks.
+///
+/// A block ends after every terminator (see `Token::is_terminator`), so
+/// `?{` closes the block computing its condition, and the closing `}` of a
+/// conditional starts the block control flow rejoins at. Nested
+/// conditionals therefore produce their blocks in stream order.
+pub fn basic_blocks(tokens: &[Token]) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if *token == Token::ENop && start < i {
+            blocks.push(start..i);
+            start = i;
+        }
+        if token.is_terminator() {
+            blocks.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < tokens.len() {
+        blocks.push(start..tokens.len());
+    }
+    blocks
+}
+
+/// The condition and body of every `?{` in `tokens`, in stream order.
+///
+/// The condition is what `basic_blocks` puts before the `?{`, starting after
+/// the previous terminator or `}`; the body runs up to the matching `}`, or
+/// the end of the stream when it is missing. Neither includes the braces.
+pub fn conditions(tokens: &[Token]) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut conditions = Vec::new();
+    // Indices into `conditions` of the blocks not closed yet.
+    let mut open = Vec::new();
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            Token::EIf => {
+                open.push(conditions.len());
+                conditions.push((start..i, i + 1..tokens.len()));
+            }
+            Token::ENop => {
+                if let Some(n) = open.pop() {
+                    conditions[n].1.end = i;
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+        if token.is_terminator() {
+            start = i + 1;
+        }
+    }
+    conditions
+}
+
+/// Number of blocks `basic_blocks` would return, without building them.
+pub fn estimate_blocks(tokens: &[Token]) -> usize {
+    let mut blocks = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if *token == Token::ENop && start < i {
+            blocks += 1;
+            start = i;
+        }
+        if token.is_terminator() {
+            blocks += 1;
+            start = i + 1;
+        }
+    }
+    if start < tokens.len() {
+        blocks += 1;
+    }
+    blocks
+}
+
+/// Number of tokens of each kind in `tokens`, ordered by kind.
+pub fn histogram(tokens: &[Token]) -> BTreeMap<TokenKind, usize> {
+    let mut counts = BTreeMap::new();
+    for token in tokens {
+        *counts.entry(token.kind()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Stable hash of the meaning of a stream, for use as a cache key.
+///
+/// Commutative operands are put in a fixed order and parser instructions
+/// stripped first, so streams that differ only in those respects collide.
+/// Operands are ordered before stripping, as the destination of a compound
+/// assignment such as `+=` is only set apart by the parser instructions.
+///
+/// Tokens are hashed by their kind and printed form (see `display_stream`)
+/// rather than their in-memory layout, so fingerprints are the same on
+/// every target and can be persisted.
+pub fn fingerprint(tokens: &[Token]) -> u64 {
+    let tokens = transform::canonicalize_commutative(tokens);
+    let tokens = transform::strip_parser_instructions(&tokens);
+    let mut hasher = FnvHasher(FNV_OFFSET_BASIS);
+    for token in &tokens {
+        // Tokens of different kinds may print the same, eg. `+` as an
+        // identifier and as an operator.
+        hasher.write_u8(kind_tag(token.kind()));
+        let text = display_stream(slice::from_ref(token));
+        hasher.write(&(text.len() as u64).to_le_bytes());
+        hasher.write(text.as_bytes());
+    }
+    hasher.finish()
+}
+
+// Fixed tag of each kind for `fingerprint`. Persisted fingerprints depend on
+// these, so they must not change.
+fn kind_tag(kind: TokenKind) -> u8 {
+    match kind {
+        TokenKind::Arithmetic => 0,
+        TokenKind::Logic => 1,
+        TokenKind::Comparison => 2,
+        TokenKind::Assignment => 3,
+        TokenKind::Memory => 4,
+        TokenKind::Control => 5,
+        TokenKind::Stack => 6,
+        TokenKind::InternalVar => 7,
+        TokenKind::Constant => 8,
+        TokenKind::Identifier => 9,
+        TokenKind::Parser => 10,
+        TokenKind::Invalid => 11,
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64-bit FNV-1a. Unlike `DefaultHasher` its output is fixed for given
+// bytes.
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::{Tokenize, Tokenizer};
+
+    #[test]
+    fn stack_depth() {
+        let tokens = Tokenizer::tokenize("1,2,3,+,+");
+        assert_eq!(max_stack_depth(&tokens), Ok(3));
+    }
+
+    #[test]
+    fn stack_depth_conditional() {
+        let tokens = Tokenizer::tokenize("eax,?{,1,2,3,+,+,ebx,=,},4");
+        assert_eq!(max_stack_depth(&tokens), Ok(3));
+    }
+
+    #[test]
+    fn instruction_effects() {
+        let fx = effects(&Tokenizer::tokenize("eax,ebx,+,ecx,="));
+        assert_eq!(fx.reads, ["eax", "ebx"].iter().map(|r| r.to_string()).collect());
+        assert_eq!(fx.writes, ["ecx"].iter().map(|r| r.to_string()).collect());
+        assert!(fx.mem_reads.is_empty() && fx.mem_writes.is_empty());
+    }
+
+    #[test]
+    fn instruction_effects_deterministic() {
+        let esil = "edx,ecx,ebx,eax,+,+,+,edi,=,esi,ebp,-,esp,=";
+        let first = format!("{:?}", effects(&Tokenizer::tokenize(esil)));
+        for _ in 0..10 {
+            assert_eq!(format!("{:?}", effects(&Tokenizer::tokenize(esil))), first);
+        }
+        let reads = effects(&Tokenizer::tokenize(esil)).reads;
+        assert_eq!(reads.into_iter().collect::<Vec<_>>(),
+                   vec!["eax", "ebp", "ebx", "ecx", "edx", "esi"]);
+    }
+
+    #[test]
+    fn instruction_effects_memory() {
+        let fx = effects(&Tokenizer::tokenize("eax,0x1000,=[4],esp,[4],ebx,="));
+        assert_eq!(fx.mem_writes, vec![MemoryAccess { addr: Some(0x1000), width: 32 }]);
+        assert_eq!(fx.mem_reads, vec![MemoryAccess { addr: None, width: 32 }]);
+        assert!(fx.reads.contains("esp") && fx.reads.contains("eax"));
+    }
+
+    #[test]
+    fn parser_instructions() {
+        // `PPop` restores copied values in their original order.
+        let mut tokens = Tokenizer::tokenize("eax,ebx");
+        tokens.extend_from_slice(&[Token::PCopy(2), Token::PPop(2), Token::EEq]);
+        let fx = effects(&tokens);
+        assert_eq!(fx.writes, ["ebx"].iter().map(|r| r.to_string()).collect());
+        assert_eq!(fx.reads, ["eax"].iter().map(|r| r.to_string()).collect());
+    }
+
+    #[test]
+    fn compound_assignment() {
+        for op in &["+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>="] {
+            let tokens = Tokenizer::tokenize(format!("ebx,eax,{}", op));
+            assert_eq!(max_stack_depth(&tokens), Ok(2));
+            let fx = effects(&tokens);
+            assert_eq!(fx.writes, ["eax"].iter().map(|r| r.to_string()).collect());
+        }
+        for op in &["!=", "++=", "--="] {
+            let tokens = Tokenizer::tokenize(format!("eax,{}", op));
+            assert!(max_stack_depth(&tokens).is_ok());
+            let fx = effects(&tokens);
+            assert_eq!(fx.writes, ["eax"].iter().map(|r| r.to_string()).collect());
+        }
+        for op in &["++", "--"] {
+            assert!(max_stack_depth(&Tokenizer::tokenize(format!("eax,{}", op))).is_ok());
+        }
+    }
+
+    #[test]
+    fn compound_memory_assignment() {
+        let fx = effects(&Tokenizer::tokenize("ebx,0x1000,-=[4]"));
+        assert_eq!(fx.mem_reads, vec![MemoryAccess { addr: Some(0x1000), width: 32 }]);
+        assert_eq!(fx.mem_writes, vec![MemoryAccess { addr: Some(0x1000), width: 32 }]);
+        assert!(fx.reads.contains("ebx"));
+    }
+
+    #[test]
+    fn code_writes() {
+        let esil = "0x90,0x401000,=[1],eax,0x8000,=[4],0,ebx,=[1]";
+        let fx = effects(&Tokenizer::tokenize(esil));
+        let code = 0x401000..0x402000;
+        assert_eq!(fx.writes_to_range(code.clone()),
+                   vec![&MemoryAccess { addr: Some(0x401000), width: 8 }]);
+        let fx = effects(&Tokenizer::tokenize("eax,0x400ffe,=[4]"));
+        assert_eq!(fx.writes_to_range(code.clone()).len(), 1);
+        // A word straddling the start of the range, whatever its size.
+        let fx = effects(&Tokenizer::tokenize("eax,0x400ffe,=[]"));
+        assert_eq!(fx.writes_to_range(code).len(), 1);
+    }
+
+    #[test]
+    fn target_flags() {
+        let tokens = Tokenizer::tokenize("$z,zf,=,$p,pf,=,$r,$$");
+        assert_eq!(validate_flags(&tokens, &FlagSet::all()), Ok(()));
+        assert_eq!(validate_flags(&tokens, &FlagSet::arm()), Err(vec![FlagError::Unsupported(3)]));
+    }
+
+    #[test]
+    fn memory_access() {
+        assert!(!contains_memory_access(&Tokenizer::tokenize("eax,ebx,+=")));
+        assert!(contains_memory_access(&Tokenizer::tokenize("eax,[4],ebx,=")));
+        assert!(contains_memory_access(&Tokenizer::tokenize("1,eax,+=[2]")));
+    }
+
+    #[test]
+    fn registers() {
+        let registers = referenced_registers(&Tokenizer::tokenize("eax,ebx,+,eax,="));
+        assert_eq!(registers.into_iter().collect::<Vec<_>>(), vec!["eax", "ebx"]);
+    }
+
+    #[test]
+    fn blocks() {
+        let tokens = Tokenizer::tokenize("eax,?{,1,ebx,=,},2,ecx,=");
+        assert_eq!(basic_blocks(&tokens), vec![0..2, 2..5, 5..9]);
+        let tokens = Tokenizer::tokenize("eax,?{,ebx,?{,1,ecx,=,},},0x100,GOTO");
+        assert_eq!(basic_blocks(&tokens), vec![0..2, 2..4, 4..7, 7..8, 8..11]);
+    }
+
+    #[test]
+    fn block_count() {
+        let tokens = Tokenizer::tokenize("zf,?{,1,eax,=,},2,ebx,=");
+        assert_eq!(estimate_blocks(&tokens), 3);
+        assert_eq!(estimate_blocks(&tokens), basic_blocks(&tokens).len());
+        assert_eq!(estimate_blocks(&[]), 0);
+    }
+
+    #[test]
+    fn conditional_blocks() {
+        let tokens = Tokenizer::tokenize("eax,0,==,?{,1,ebx,=,}");
+        assert_eq!(conditions(&tokens), vec![(0..3, 4..7)]);
+        let tokens = Tokenizer::tokenize("$z,?{,$c,?{,1,eax,=,},},ebx,?{,2,eax,=");
+        assert_eq!(conditions(&tokens), vec![(0..1, 2..8), (2..3, 4..7), (9..10, 11..14)]);
+    }
+
+    #[test]
+    fn kind_histogram() {
+        let counts = histogram(&Tokenizer::tokenize("eax,ebx,+,4,*,ecx,="));
+        assert_eq!(counts[&TokenKind::Arithmetic], 2);
+        assert_eq!(counts[&TokenKind::Identifier], 3);
+        assert_eq!(counts.get(&TokenKind::Memory), None);
+    }
+
+    #[test]
+    fn stream_fingerprint() {
+        assert_eq!(fingerprint(&Tokenizer::tokenize("4,eax,+")),
+                   fingerprint(&Tokenizer::tokenize("eax,4,+")));
+        assert!(fingerprint(&Tokenizer::tokenize("4,eax,+")) !=
+                fingerprint(&Tokenizer::tokenize("4,eax,-")));
+        // The same whatever the target.
+        assert_eq!(fingerprint(&Tokenizer::tokenize("eax")), 0x85bcc725810a3b07);
+        // The destinations differ.
+        assert!(fingerprint(&Tokenizer::tokenize("ebx,eax,+=")) !=
+                fingerprint(&Tokenizer::tokenize("eax,ebx,+=")));
+        // Identifiers printed like other tokens.
+        let id = |name: &str| [Token::EIdentifier(name.to_owned())];
+        assert!(fingerprint(&id("+")) != fingerprint(&[Token::EAdd]));
+        assert!(fingerprint(&id("0x4")) != fingerprint(&[Token::EConstant(4)]));
+        assert!(fingerprint(&id("$z")) != fingerprint(&[Token::IZero(None)]));
+    }
+
+    #[test]
+    fn underflow_address() {
+        let mut tokens = Vec::new();
+        let mut index = Vec::new();
+        let tokenizer = Tokenizer::new();
+        tokenizer.tokenize_append("4,eax,+=", 0x1000, &mut tokens, &mut index);
+        tokenizer.tokenize_append("ebx,=", 0x1003, &mut tokens, &mut index);
+        let StackError::Underflow(pos) = max_stack_depth(&tokens).unwrap_err();
+        assert_eq!(instruction_address(&index, pos), Some(0x1003));
+        assert_eq!(instruction_address(&index, 2), Some(0x1000));
+    }
+
+    #[test]
+    fn checked() {
+        assert_eq!(tokenize_checked("4,eax,+=,ebx,eax,="),
+                   Ok(Tokenizer::tokenize("4,eax,+=,ebx,eax,=")));
+        assert_eq!(tokenize_checked("eax,+,ebx"), Err(CheckError::Underflow(1)));
+        assert_eq!(tokenize_checked("4,eax,+"), Err(CheckError::Unbalanced(1)));
+    }
+
+    #[test]
+    fn push_pattern() {
+        let tokens = tokenize_checked("rax,rsp,=[8],8,rsp,-=").unwrap();
+        let effects = effects(&tokens);
+        assert_eq!(effects.writes.into_iter().collect::<Vec<_>>(), vec!["rsp"]);
+        assert_eq!(effects.reads.into_iter().collect::<Vec<_>>(), vec!["rax", "rsp"]);
+        assert_eq!(effects.mem_writes, vec![MemoryAccess { addr: None, width: 64 }]);
+        // `rsp` (v3) is pushed last, making it the first operand of `-`, and
+        // `rsp - 8` (v4) is stored back into it.
+        let ops = ::ssa::to_ssa(&tokens).unwrap();
+        assert_eq!((ops[5].op.clone(), ops[5].operands.clone()), (Token::ESub, vec![2, 3]));
+        assert_eq!(ops[6].operands, vec![4, 3]);
+    }
+
+    #[test]
+    fn stack_depth_underflow() {
+        let tokens = Tokenizer::tokenize("1,+");
+        assert_eq!(max_stack_depth(&tokens), Err(StackError::Underflow(1)));
+    }
+}
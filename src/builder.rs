@@ -0,0 +1,50 @@
+use lexer::Token;
+use serializer::{to_radare_esil, SerializeError};
+
+/// Builds a token stream one token at a time.
+#[derive(Debug, Clone, Default)]
+pub struct EsilBuilder {
+    tokens: Vec<Token>,
+}
+
+impl EsilBuilder {
+    pub fn new() -> EsilBuilder {
+        EsilBuilder::default()
+    }
+
+    pub fn constant(self, value: u64) -> EsilBuilder {
+        self.op(Token::EConstant(value))
+    }
+
+    pub fn identifier<T: Into<String>>(self, name: T) -> EsilBuilder {
+        self.op(Token::EIdentifier(name.into()))
+    }
+
+    /// Append any token.
+    pub fn op(mut self, token: Token) -> EsilBuilder {
+        self.tokens.push(token);
+        self
+    }
+
+    pub fn build(self) -> Vec<Token> {
+        self.tokens
+    }
+
+    /// The stream as an ESIL string, see `serializer::to_radare_esil`.
+    pub fn to_esil(&self) -> Result<String, SerializeError> {
+        to_radare_esil(&self.tokens)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::{Tokenize, Tokenizer};
+
+    #[test]
+    fn build() {
+        let builder = EsilBuilder::new().constant(4).identifier("eax").op(Token::EAdd);
+        assert_eq!(builder.to_esil(), Ok("0x4,eax,+".to_owned()));
+        assert_eq!(builder.build(), Tokenizer::tokenize("4,eax,+"));
+    }
+}
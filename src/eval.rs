@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use lexer::{SideEffect, Token};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// The token at this index writes registers or memory, or transfers
+    /// control.
+    SideEffect(usize),
+    /// The token at this index cannot be evaluated without more state, eg.
+    /// a memory read or a flag.
+    Unsupported(usize),
+    /// The token at this index needs more values than are available.
+    Underflow(usize),
+    /// Division or modulo by zero at this index.
+    DivisionByZero(usize),
+    /// A register missing from the snapshot.
+    UnknownRegister(String),
+    /// The stream leaves this many values on the stack instead of one.
+    NotSingleValue(usize),
+}
+
+/// Evaluate a pure expression, such as an address computation, against a
+/// read-only snapshot of the registers, returning the single value it
+/// leaves on the stack.
+///
+/// Binary operators take the top of the stack as their first operand, so
+/// `eax,4,-` is `4 - eax`. Arithmetic wraps at 64 bits.
+pub fn eval_pure(tokens: &[Token], regs: &HashMap<String, u64>) -> Result<u64, EvalError> {
+    eval(tokens, regs, None, &mut |_, _| {})
+}
+
+/// `eval_pure`, calling `on_register_read` with the name and value of each
+/// register as it is read, eg. for taint tracking.
+pub fn eval_pure_observed<F>(tokens: &[Token],
+                             regs: &HashMap<String, u64>,
+                             mut on_register_read: F) -> Result<u64, EvalError>
+    where F: FnMut(&str, u64)
+{
+    eval(tokens, regs, None, &mut on_register_read)
+}
+
+/// An operator evaluated by `eval_pure_traced`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub token: Token,
+    /// Values consumed, first operand first, ie. from the top of the stack
+    /// down.
+    pub operands: Vec<u64>,
+    pub result: Option<u64>,
+}
+
+/// `eval_pure`, also returning each operator evaluated with its operands
+/// and result, eg. `4 + 3 = 7` for `3,4,+`. Pushing operands and moving
+/// values around the stack are not recorded.
+pub fn eval_pure_traced(tokens: &[Token],
+                        regs: &HashMap<String, u64>) -> Result<(u64, Vec<TraceEntry>), EvalError> {
+    let mut trace = Vec::new();
+    let value = eval(tokens, regs, Some(&mut trace), &mut |_, _| {})?;
+    Ok((value, trace))
+}
+
+fn eval(tokens: &[Token],
+        regs: &HashMap<String, u64>,
+        mut trace: Option<&mut Vec<TraceEntry>>,
+        on_register_read: &mut dyn FnMut(&str, u64)) -> Result<u64, EvalError> {
+    let mut stack: Vec<u64> = Vec::new();
+    let mut temp: Vec<u64> = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.side_effect() != SideEffect::Pure {
+            return Err(EvalError::SideEffect(i));
+        }
+        let (pop, _) = token.stack_effect();
+        if stack.len() < pop {
+            return Err(EvalError::Underflow(i));
+        }
+        let from = stack.len() - pop;
+        let args = stack[from..].iter().rev().cloned().collect::<Vec<_>>();
+        let value = match *token {
+            Token::EConstant(v) => v,
+            Token::EIdentifier(ref name) => {
+                match regs.get(name) {
+                    Some(&v) => {
+                        on_register_read(name, v);
+                        v
+                    }
+                    None => return Err(EvalError::UnknownRegister(name.clone())),
+                }
+            }
+            Token::EAdd => args[0].wrapping_add(args[1]),
+            Token::ESub => args[0].wrapping_sub(args[1]),
+            Token::EMul => args[0].wrapping_mul(args[1]),
+            Token::EDiv | Token::EMod if args[1] == 0 => {
+                return Err(EvalError::DivisionByZero(i));
+            }
+            Token::EDiv => args[0] / args[1],
+            Token::EMod => args[0] % args[1],
+            Token::EAnd => args[0] & args[1],
+            Token::EOr => args[0] | args[1],
+            Token::EXor => args[0] ^ args[1],
+            Token::ELsl => {
+                u32::try_from(args[1]).ok().and_then(|s| args[0].checked_shl(s)).unwrap_or(0)
+            }
+            Token::ELsr => {
+                u32::try_from(args[1]).ok().and_then(|s| args[0].checked_shr(s)).unwrap_or(0)
+            }
+            Token::ERol => args[0].rotate_left((args[1] % 64) as u32),
+            Token::ERor => args[0].rotate_right((args[1] % 64) as u32),
+            Token::ELt => (args[0] < args[1]) as u64,
+            Token::EGt => (args[0] > args[1]) as u64,
+            Token::ENeg => (args[0] == 0) as u64,
+            Token::EPick | Token::ERpick => {
+                let depth = stack.len() - 1;
+                let n = args[0] as usize;
+                if n >= depth {
+                    return Err(EvalError::Underflow(i));
+                }
+                if *token == Token::EPick {
+                    stack[depth - 1 - n]
+                } else {
+                    stack[n]
+                }
+            }
+            Token::EPop => {
+                stack.pop();
+                continue;
+            }
+            Token::EDup => {
+                stack.push(args[0]);
+                continue;
+            }
+            Token::EClear => {
+                stack.clear();
+                continue;
+            }
+            Token::PCopy(_) => {
+                temp.extend_from_slice(&stack[from..]);
+                continue;
+            }
+            Token::PPop(n) => {
+                if temp.len() < n {
+                    return Err(EvalError::Underflow(i));
+                }
+                let at = temp.len() - n;
+                stack.extend(temp.drain(at..));
+                continue;
+            }
+            Token::PSync => continue,
+            _ => return Err(EvalError::Unsupported(i)),
+        };
+        if let (Some(trace), true) = (trace.as_mut(), pop > 0) {
+            trace.push(TraceEntry {
+                token: token.clone(),
+                operands: args,
+                result: Some(value),
+            });
+        }
+        stack.truncate(from);
+        stack.push(value);
+    }
+    match stack.len() {
+        1 => Ok(stack[0]),
+        n => Err(EvalError::NotSingleValue(n)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::{Tokenize, Tokenizer};
+
+    fn eval_esil(esil: &str) -> Result<u64, EvalError> {
+        let mut regs = HashMap::new();
+        regs.insert("eax".to_owned(), 0x1000);
+        eval_pure(&Tokenizer::tokenize(esil), &regs)
+    }
+
+    #[test]
+    fn pure_expression() {
+        assert_eq!(eval_esil("eax,4,+"), Ok(0x1004));
+        assert_eq!(eval_esil("4,eax,-"), Ok(0xffc));
+        assert_eq!(eval_esil("2,eax,<="), Ok(0));
+        assert_eq!(eval_esil("eax,2,*,1,+"), Ok(0x2001));
+        // Shifts by 64 bits or more clear the value.
+        assert_eq!(eval_esil("64,1,<<"), Ok(0));
+        assert_eq!(eval_esil("0x100000000,1,<<"), Ok(0));
+        assert_eq!(eval_esil("0x100000000,eax,>>"), Ok(0));
+    }
+
+    #[test]
+    fn comparison_booleans() {
+        // The top of the stack is the left operand: `2,eax,<=` is `eax <= 2`.
+        for &(rhs, lt, gt, le, ge) in &[(0x2000, 1, 0, 1, 0),
+                                        (0x1000, 0, 0, 1, 1),
+                                        (0x10, 0, 1, 0, 1)] {
+            assert_eq!(eval_esil(&format!("{},eax,<", rhs)), Ok(lt));
+            assert_eq!(eval_esil(&format!("{},eax,>", rhs)), Ok(gt));
+            assert_eq!(eval_esil(&format!("{},eax,<=", rhs)), Ok(le));
+            assert_eq!(eval_esil(&format!("{},eax,>=", rhs)), Ok(ge));
+        }
+    }
+
+    #[test]
+    fn trace() {
+        let mut regs = HashMap::new();
+        regs.insert("eax".to_owned(), 2);
+        let (value, trace) = eval_pure_traced(&Tokenizer::tokenize("3,4,+,eax,*"), &regs).unwrap();
+        assert_eq!(value, 14);
+        assert_eq!(trace[0], TraceEntry {
+            token: Token::EAdd,
+            operands: vec![4, 3],
+            result: Some(7),
+        });
+        assert_eq!(trace.len(), 2);
+    }
+
+    #[test]
+    fn register_reads() {
+        let mut regs = HashMap::new();
+        regs.insert("eax".to_owned(), 2);
+        let mut reads = Vec::new();
+        let value = eval_pure_observed(&Tokenizer::tokenize("eax,4,+"), &regs, |name, v| {
+            reads.push((name.to_owned(), v))
+        });
+        assert_eq!(value, Ok(6));
+        assert_eq!(reads, vec![("eax".to_owned(), 2)]);
+    }
+
+    #[test]
+    fn pure_errors() {
+        assert_eq!(eval_esil("4,eax,="), Err(EvalError::SideEffect(2)));
+        assert_eq!(eval_esil("eax,[4]"), Err(EvalError::Unsupported(1)));
+        assert_eq!(eval_esil("ebx,4,+"), Err(EvalError::UnknownRegister("ebx".to_owned())));
+        assert_eq!(eval_esil("eax,4"), Err(EvalError::NotSingleValue(2)));
+        assert_eq!(eval_esil("0,eax,/"), Err(EvalError::DivisionByZero(2)));
+        assert_eq!(eval_esil("+"), Err(EvalError::Underflow(0)));
+    }
+}
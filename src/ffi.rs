@@ -0,0 +1,158 @@
+//! C interface to the tokenizer, enabled by the `ffi` feature.
+//!
+//! `esil_tokenize` returns an `EsilTokens` owned by the caller, who must
+//! release it with `esil_tokens_free`. Strings returned by `esil_token_text`
+//! belong to the `EsilTokens` and are valid until it is freed.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::slice;
+
+use lexer::{Token, Tokenize, Tokenizer};
+use serializer::display_stream;
+
+/// Opaque array of tokens.
+pub struct EsilTokens {
+    tokens: Vec<Token>,
+    texts: Vec<CString>,
+}
+
+impl EsilTokens {
+    unsafe fn get<'a>(tokens: *const EsilTokens, index: usize) -> Option<&'a Token> {
+        tokens.as_ref().and_then(|t| t.tokens.get(index))
+    }
+}
+
+/// Tokenize the NUL-terminated string `esil` with the default options.
+/// Returns null if `esil` is null or not UTF-8.
+///
+/// # Safety
+///
+/// `esil` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn esil_tokenize(esil: *const c_char) -> *mut EsilTokens {
+    if esil.is_null() {
+        return ptr::null_mut();
+    }
+    let esil = match CStr::from_ptr(esil).to_str() {
+        Ok(esil) => esil,
+        Err(_) => return ptr::null_mut(),
+    };
+    let tokens = Tokenizer::tokenize(esil);
+    // Identifiers cannot contain NUL since they come from a C string.
+    let texts = tokens.iter()
+                      .map(|t| CString::new(display_stream(slice::from_ref(t))).unwrap_or_default())
+                      .collect();
+    Box::into_raw(Box::new(EsilTokens { tokens, texts }))
+}
+
+/// Number of tokens, or 0 if `tokens` is null.
+///
+/// # Safety
+///
+/// `tokens` must be null or come from `esil_tokenize` and not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn esil_tokens_len(tokens: *const EsilTokens) -> usize {
+    tokens.as_ref().map_or(0, |t| t.tokens.len())
+}
+
+/// `TokenKind` of the token at `index`, as its declaration order in Rust
+/// (0 for `Arithmetic`), or -1 if out of range.
+///
+/// # Safety
+///
+/// As for `esil_tokens_len`.
+#[no_mangle]
+pub unsafe extern "C" fn esil_token_kind(tokens: *const EsilTokens, index: usize) -> c_int {
+    EsilTokens::get(tokens, index).map_or(-1, |t| t.kind() as c_int)
+}
+
+/// Mnemonic of the token at `index`, eg. `+`, `[4]` or the name of a
+/// register, or null if out of range.
+///
+/// # Safety
+///
+/// As for `esil_tokens_len`.
+#[no_mangle]
+pub unsafe extern "C" fn esil_token_text(tokens: *const EsilTokens, index: usize) -> *const c_char {
+    tokens.as_ref()
+          .and_then(|t| t.texts.get(index))
+          .map_or(ptr::null(), |text| text.as_ptr())
+}
+
+/// Store the numeric payload of the token at `index` in `value`: the value
+/// of a constant, the width in bits of a memory access, the bit of an
+/// internal var or the count of a parser instruction. Returns false,
+/// leaving `value` untouched, if the token has none or is out of range.
+///
+/// # Safety
+///
+/// As for `esil_tokens_len`, and `value` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn esil_token_value(tokens: *const EsilTokens,
+                                          index: usize,
+                                          value: *mut u64) -> bool {
+    let payload = match EsilTokens::get(tokens, index) {
+        Some(&Token::EConstant(v)) => Some(v),
+        Some(&Token::EPeek(bits)) | Some(&Token::EPoke(bits)) => Some(u64::from(bits)),
+        Some(&Token::IZero(Some(bit))) |
+        Some(&Token::ICarry(Some(bit))) |
+        Some(&Token::IParity(Some(bit))) |
+        Some(&Token::IOverflow(Some(bit))) |
+        Some(&Token::ISign(Some(bit))) |
+        Some(&Token::IBorrow(Some(bit))) |
+        Some(&Token::ISize(Some(bit))) |
+        Some(&Token::IAddress(Some(bit))) => Some(u64::from(bit)),
+        Some(&Token::PCopy(n)) | Some(&Token::PPop(n)) => Some(n as u64),
+        _ => None,
+    };
+    match (payload, value.as_mut()) {
+        (Some(payload), Some(value)) => {
+            *value = payload;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Free tokens returned by `esil_tokenize`. Does nothing if null.
+///
+/// # Safety
+///
+/// `tokens` must be null or come from `esil_tokenize`, and not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn esil_tokens_free(tokens: *mut EsilTokens) {
+    if !tokens.is_null() {
+        drop(Box::from_raw(tokens));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::TokenKind;
+
+    #[test]
+    fn round_trip() {
+        let esil = CString::new("0x10,eax,+=[4]").unwrap();
+        unsafe {
+            let tokens = esil_tokenize(esil.as_ptr());
+            assert!(!tokens.is_null());
+            assert_eq!(esil_tokens_len(tokens), 7);
+            assert_eq!(esil_token_kind(tokens, 0), TokenKind::Constant as c_int);
+            assert_eq!(esil_token_kind(tokens, 7), -1);
+            let text = CStr::from_ptr(esil_token_text(tokens, 1));
+            assert_eq!(text.to_str(), Ok("eax"));
+            let mut value = 0;
+            assert!(esil_token_value(tokens, 0, &mut value));
+            assert_eq!(value, 0x10);
+            assert!(esil_token_value(tokens, 3, &mut value));
+            assert_eq!(value, 32);
+            assert!(!esil_token_value(tokens, 1, &mut value));
+            esil_tokens_free(tokens);
+            assert!(esil_tokenize(ptr::null()).is_null());
+        }
+    }
+}
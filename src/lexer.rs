@@ -1,17 +1,28 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::cmp;
+use std::iter;
+use std::ops::Range;
+use std::sync::OnceLock;
 use num::traits::Num;
 
 
 const ESIL_INTERNAL_PREFIX: char = '$';
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Token {
+/// An ESIL token, generic over how identifier names are stored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum GenericToken<S> {
     // Esil Opcodes
     EInterrupt,
     ECmp,
     ELt,
     EGt,
+    /// `=`, which like any other operation updates the flags from the value
+    /// assigned.
     EEq,
+    /// `:=`, assigning without updating the flags.
+    EWeakEq,
     EIf,
     ELsl,
     ELsr,
@@ -20,196 +31,923 @@ pub enum Token {
     EAnd,
     EOr,
     ENop,
+    /// `!`, logical negation: 1 for a zero operand and 0 otherwise.
     ENeg,
     EMul,
+    /// `L*`, the full 128-bit product, pushed as two 64-bit halves.
+    ELongMul,
     EXor,
     EAdd,
     ESub,
     EDiv,
     EMod,
-    EPoke(u8),
-    EPeek(u8),
+    /// Memory store and load, with the width in bits. The width is 0 for the
+    /// unsized forms (`[]`, `[*]`, `=[]`...), which access a word.
+    EPoke(u16),
+    EPeek(u16),
     EDump,
     EPop,
     ETodo,
     EGoto,
+    /// `REPEAT`, jumping back to a token index while a counter is above 1.
+    ERepeat,
     EBreak,
+    /// `CLEAR`, which only empties the ESIL stack. radare2 has no other
+    /// clearing operator.
     EClear,
     EDup,
     ETrap,
-    // Esil Internal Vars
-    IZero(u8),
-    ICarry(u8),
-    IParity(u8),
-    IOverflow(u8),
-    ISign(u8),
-    IBorrow(u8),
-    ISize(u8),
-    IAddress(u8),
+    /// `PICK`, pushing a copy of the nth value from the top of the stack,
+    /// with `n` popped first (`0,PICK` behaves like `DUP`).
+    EPick,
+    /// `RPICK`, like `PICK` but counting from the bottom of the stack.
+    ERpick,
+    // Esil Internal Vars. The bit is `None` when the var has no numeric
+    // suffix (eg. `$c` as opposed to `$c0`).
+    IZero(Option<u8>),
+    ICarry(Option<u8>),
+    IParity(Option<u8>),
+    IOverflow(Option<u8>),
+    ISign(Option<u8>),
+    IBorrow(Option<u8>),
+    /// `$r`, the word size, or `$r32` for an explicit size of 32 bits.
+    ISize(Option<u8>),
+    IAddress(Option<u8>),
     // Esil Operands
     EConstant(u64),
-    EIdentifier(String),
+    EIdentifier(S),
     // Invalid
     EInvalid,
-    // Parser Instructions.
+    // Parser Instructions. Compound operators are expanded using a
+    // temporary stack kept alongside the ESIL stack.
+    /// Copies the top `n` values of the stack onto the temporary stack,
+    /// leaving the stack unchanged.
     PCopy(usize),
+    /// Moves the top `n` values of the temporary stack back onto the stack,
+    /// keeping their order. `PCopy(2), PPop(2)` turns `[a, b]` into
+    /// `[a, b, a, b]`.
     PPop(usize),
     PSync,
 }
 
+impl<S> GenericToken<S> {
+    /// Number of values the token pops from and pushes onto the ESIL stack.
+    ///
+    /// `EClear` empties the stack whatever its depth and is reported as
+    /// `(0, 0)`. `PCopy(n)` needs `n` values on the stack and leaves them in
+    /// place, while `PPop(n)` pushes `n` values back from the parser's
+    /// temporary stack. As in radare2, `==` only sets the flags.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match *self {
+            GenericToken::ELt |
+            GenericToken::EGt |
+            GenericToken::ELsl |
+            GenericToken::ELsr |
+            GenericToken::ERor |
+            GenericToken::ERol |
+            GenericToken::EAnd |
+            GenericToken::EOr |
+            GenericToken::EMul |
+            GenericToken::EXor |
+            GenericToken::EAdd |
+            GenericToken::ESub |
+            GenericToken::EDiv |
+            GenericToken::EMod => (2, 1),
+            GenericToken::ECmp |
+            GenericToken::EEq |
+            GenericToken::EWeakEq |
+            GenericToken::ERepeat |
+            GenericToken::EPoke(_) => (2, 0),
+            GenericToken::ENeg |
+            GenericToken::EPick |
+            GenericToken::ERpick |
+            GenericToken::EPeek(_) => (1, 1),
+            GenericToken::EInterrupt |
+            GenericToken::EIf |
+            GenericToken::EPop |
+            GenericToken::EGoto |
+            GenericToken::ETrap => (1, 0),
+            GenericToken::EDup => (1, 2),
+            GenericToken::ELongMul => (2, 2),
+            GenericToken::ENop |
+            GenericToken::EDump |
+            GenericToken::ETodo |
+            GenericToken::EBreak |
+            GenericToken::EClear |
+            GenericToken::EInvalid |
+            GenericToken::PSync => (0, 0),
+            GenericToken::IZero(_) |
+            GenericToken::ICarry(_) |
+            GenericToken::IParity(_) |
+            GenericToken::IOverflow(_) |
+            GenericToken::ISign(_) |
+            GenericToken::IBorrow(_) |
+            GenericToken::ISize(_) |
+            GenericToken::IAddress(_) |
+            GenericToken::EConstant(_) |
+            GenericToken::EIdentifier(_) => (0, 1),
+            GenericToken::PCopy(n) => (n, n),
+            GenericToken::PPop(n) => (0, n),
+        }
+    }
+
+    /// Number of operands the token pops off the stack.
+    pub fn arity(&self) -> usize {
+        self.stack_effect().0
+    }
+
+    pub fn kind(&self) -> TokenKind {
+        match *self {
+            GenericToken::EAdd |
+            GenericToken::ESub |
+            GenericToken::EMul |
+            GenericToken::ELongMul |
+            GenericToken::EDiv |
+            GenericToken::EMod => TokenKind::Arithmetic,
+            GenericToken::ELsl |
+            GenericToken::ELsr |
+            GenericToken::ERor |
+            GenericToken::ERol |
+            GenericToken::EAnd |
+            GenericToken::EOr |
+            GenericToken::EXor |
+            GenericToken::ENeg => TokenKind::Logic,
+            GenericToken::ECmp |
+            GenericToken::ELt |
+            GenericToken::EGt => TokenKind::Comparison,
+            GenericToken::EEq |
+            GenericToken::EWeakEq => TokenKind::Assignment,
+            GenericToken::EPoke(_) |
+            GenericToken::EPeek(_) => TokenKind::Memory,
+            GenericToken::EInterrupt |
+            GenericToken::EIf |
+            GenericToken::ENop |
+            GenericToken::ETodo |
+            GenericToken::EGoto |
+            GenericToken::ERepeat |
+            GenericToken::EBreak |
+            GenericToken::ETrap => TokenKind::Control,
+            GenericToken::EDump |
+            GenericToken::EPop |
+            GenericToken::EClear |
+            GenericToken::EPick |
+            GenericToken::ERpick |
+            GenericToken::EDup => TokenKind::Stack,
+            GenericToken::IZero(_) |
+            GenericToken::ICarry(_) |
+            GenericToken::IParity(_) |
+            GenericToken::IOverflow(_) |
+            GenericToken::ISign(_) |
+            GenericToken::IBorrow(_) |
+            GenericToken::ISize(_) |
+            GenericToken::IAddress(_) => TokenKind::InternalVar,
+            GenericToken::EConstant(_) => TokenKind::Constant,
+            GenericToken::EIdentifier(_) => TokenKind::Identifier,
+            GenericToken::EInvalid => TokenKind::Invalid,
+            GenericToken::PCopy(_) |
+            GenericToken::PPop(_) |
+            GenericToken::PSync => TokenKind::Parser,
+        }
+    }
+
+    /// Whether the token ends a basic block: `GOTO`, `REPEAT`, `BREAK` and
+    /// `TRAP` transfer control, and `?{` starts a block that may be skipped.
+    pub fn is_terminator(&self) -> bool {
+        matches!(*self, GenericToken::EGoto |
+                        GenericToken::ERepeat |
+                        GenericToken::EBreak |
+                        GenericToken::ETrap |
+                        GenericToken::EIf)
+    }
+
+    pub fn as_constant(&self) -> Option<u64> {
+        match *self {
+            GenericToken::EConstant(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Replace the value of an `EConstant`. Returns false, leaving the token
+    /// unchanged, for any other token.
+    pub fn set_constant(&mut self, value: u64) -> bool {
+        match *self {
+            GenericToken::EConstant(ref mut v) => {
+                *v = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the token only pushes a value: a constant, an identifier or
+    /// an internal var.
+    pub fn is_operand(&self) -> bool {
+        self.stack_effect() == (0, 1)
+    }
+
+    /// Whether the token is a binary operator whose operands can be swapped.
+    /// `==` is not, since the flags it sets depend on the operand order.
+    pub fn is_commutative(&self) -> bool {
+        matches!(*self, GenericToken::EAnd |
+                        GenericToken::EOr |
+                        GenericToken::EMul |
+                        GenericToken::EXor |
+                        GenericToken::EAdd)
+    }
+
+    /// Short human readable description of the token, including what it
+    /// pops and pushes. `a` is the value popped first (the top of the stack)
+    /// and `b` the one below it.
+    pub fn describe(&self) -> String {
+        let binary = |op: &str| format!("{} (pop a, pop b, push a {} b)", op, op);
+        let bytes = |bits: u16| match bits / 8 {
+            0 => "a word".to_owned(),
+            1 => "1 byte".to_owned(),
+            n => format!("{} bytes", n),
+        };
+        let flag = |name: &str, bit: &Option<u8>| match *bit {
+            Some(bit) => format!("{} flag for bit {} of the last operation (push it)", name, bit),
+            None => format!("{} flag of the last operation (push it)", name),
+        };
+        match *self {
+            GenericToken::EInterrupt => "raise interrupt (pop its number)".to_owned(),
+            GenericToken::ECmp => "compare (pop a, pop b, set flags from a - b)".to_owned(),
+            GenericToken::ELt => binary("<"),
+            GenericToken::EGt => binary(">"),
+            GenericToken::EEq => "assign (pop destination, pop value)".to_owned(),
+            GenericToken::EWeakEq => {
+                "assign without updating flags (pop destination, pop value)".to_owned()
+            }
+            GenericToken::EIf => {
+                "start conditional block (pop condition, skip to `}` if zero)".to_owned()
+            }
+            GenericToken::ELsl => binary("<<"),
+            GenericToken::ELsr => binary(">>"),
+            GenericToken::ERor => binary(">>>"),
+            GenericToken::ERol => binary("<<<"),
+            GenericToken::EAnd => binary("&"),
+            GenericToken::EOr => binary("|"),
+            GenericToken::ENop => "end conditional block".to_owned(),
+            GenericToken::ENeg => "logical not (pop a, push 1 if a is zero, else 0)".to_owned(),
+            GenericToken::EMul => binary("*"),
+            GenericToken::ELongMul => {
+                "long multiply (pop a, pop b, push both 64-bit halves of a * b)".to_owned()
+            }
+            GenericToken::EXor => binary("^"),
+            GenericToken::EAdd => binary("+"),
+            GenericToken::ESub => binary("-"),
+            GenericToken::EDiv => binary("/"),
+            GenericToken::EMod => binary("%"),
+            GenericToken::EPoke(bits) => {
+                format!("store {} to memory (pop addr, pop value)", bytes(bits))
+            }
+            GenericToken::EPeek(bits) => {
+                format!("load {} from memory (pop addr, push value)", bytes(bits))
+            }
+            GenericToken::EDump => "dump the stack".to_owned(),
+            GenericToken::EPop => "discard the top of the stack (pop a)".to_owned(),
+            GenericToken::ETodo => "unimplemented operation, stops evaluation".to_owned(),
+            GenericToken::EGoto => "jump within the expression (pop token index)".to_owned(),
+            GenericToken::ERepeat => {
+                "jump back while the count is above 1 (pop token index, pop count)".to_owned()
+            }
+            GenericToken::EBreak => "stop evaluating the expression".to_owned(),
+            GenericToken::EClear => "empty the stack".to_owned(),
+            GenericToken::EDup => {
+                "duplicate the top of the stack (pop a, push a, push a)".to_owned()
+            }
+            GenericToken::ETrap => "raise trap (pop its code)".to_owned(),
+            GenericToken::EPick => {
+                "copy from the top (pop n, push the nth value from the top)".to_owned()
+            }
+            GenericToken::ERpick => {
+                "copy from the bottom (pop n, push the nth value from the bottom)".to_owned()
+            }
+            GenericToken::IZero(ref bit) => flag("zero", bit),
+            GenericToken::ICarry(ref bit) => flag("carry", bit),
+            GenericToken::IParity(ref bit) => flag("parity", bit),
+            GenericToken::IOverflow(ref bit) => flag("overflow", bit),
+            GenericToken::ISign(ref bit) => flag("sign", bit),
+            GenericToken::IBorrow(ref bit) => flag("borrow", bit),
+            GenericToken::ISize(None) => "word size in bits (push it)".to_owned(),
+            GenericToken::ISize(Some(bits)) => format!("size of {} bits (push {})", bits, bits),
+            GenericToken::IAddress(_) => "address of the instruction (push it)".to_owned(),
+            GenericToken::EConstant(v) => format!("constant 0x{:x} (push it)", v),
+            GenericToken::EIdentifier(_) => "register or flag (push it)".to_owned(),
+            GenericToken::EInvalid => "invalid token".to_owned(),
+            GenericToken::PCopy(n) => format!("copy the top {} values to the temporary stack", n),
+            GenericToken::PPop(n) => format!("move {} values back from the temporary stack", n),
+            GenericToken::PSync => "parser synchronization point".to_owned(),
+        }
+    }
+
+    pub fn side_effect(&self) -> SideEffect {
+        match *self {
+            GenericToken::EEq |
+            GenericToken::EWeakEq => SideEffect::Store,
+            GenericToken::EPoke(_) => SideEffect::MemoryWrite,
+            GenericToken::EInterrupt |
+            GenericToken::EGoto |
+            GenericToken::ERepeat |
+            GenericToken::EBreak |
+            GenericToken::ETrap => SideEffect::Control,
+            _ => SideEffect::Pure,
+        }
+    }
+}
+
+/// Broad classification of tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TokenKind {
+    /// `+`, `-`, `*`, `/`, `%`.
+    Arithmetic,
+    /// Bitwise operators, shifts, rotations and `!`.
+    Logic,
+    /// `==`, `<`, `>`.
+    Comparison,
+    /// `=`.
+    Assignment,
+    /// Memory peeks and pokes.
+    Memory,
+    /// Conditionals, jumps, interrupts and traps.
+    Control,
+    /// Operators working on the ESIL stack itself (`POP`, `DUP`, ...).
+    Stack,
+    InternalVar,
+    Constant,
+    Identifier,
+    /// `PCopy`, `PPop` and `PSync`.
+    Parser,
+    Invalid,
+}
+
+/// State changed by evaluating a token, beyond the ESIL stack itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SideEffect {
+    /// Only reads state and works on the stack.
+    Pure,
+    /// Assignment. The destination, usually a register, is popped from the
+    /// stack so it is only known in context.
+    Store,
+    /// Writes memory.
+    MemoryWrite,
+    /// Transfers control or signals the host (interrupts, traps, goto).
+    Control,
+}
+
+/// Token owning its identifier names.
+pub type Token = GenericToken<String>;
+/// Token borrowing its identifier names from the tokenized string.
+pub type BorrowedToken<'a> = GenericToken<&'a str>;
+
 pub trait Tokenize {
     type Token: Clone + Debug + PartialEq;
     fn tokenize<T: AsRef<str>>(esil: T) -> Vec<Self::Token>;
 }
 
-pub struct Tokenizer;
+/// Every operator recognized by the lexer. When several operators expand to
+/// the same tokens, the first one listed is the canonical spelling.
+pub const OPERATORS: &[&str] = &[
+    "$", "==", "<", ">", "<=", ">=", "?{", "<<", "<<=", ">>", ">>=", ">>>",
+    "<<<", "&", "&=", "}", "|", "|=", "!", "!=", "=", ":=", "*", "*=", "L*",
+    "^", "^=", "+", "+=", "++", "++=", "-", "-=", "--", "--=", "/", "/=", "%",
+    "%=", "STACK", "POP", "TODO", "GOTO", "REPEAT", "BREAK", "CLEAR", "DUP",
+    "TRAP", "PICK", "RPICK",
+    "=[1]", "=[2]", "=[4]", "=[8]", "=[]", "=[*]", "|=[1]", "|=[2]", "|=[4]",
+    "|=[8]", "|=[]", "^=[1]", "^=[2]", "^=[4]", "^=[8]", "^=[]", "&=[1]",
+    "&=[2]", "&=[4]", "&=[8]", "&=[]", "+=[1]", "+=[2]", "+=[4]", "+=[8]",
+    "+=[]", "-=[1]", "-=[2]", "-=[4]", "-=[8]", "-=[]", "%=[1]", "%=[2]",
+    "%=[4]", "%=[8]", "%=[]", "/=[1]", "/=[2]", "/=[4]", "/=[8]", "/=[]",
+    "*=[1]", "*=[2]", "*=[4]", "*=[8]", "*=[]", "++=[1]", "++=[2]", "++=[4]",
+    "++=[8]", "++=[]", "--=[1]", "--=[2]", "--=[4]", "--=[8]", "--=[]", "[1]",
+    "[2]", "[4]", "[8]", "[]", "[*]",
+];
+
+/// Something suspicious found by `Tokenizer::tokenize_full`, with the span
+/// of the word it is about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// A word starting with a digit that is not a valid constant, eg. `0xzz`.
+    /// It is kept as an identifier.
+    MalformedConstant(Range<usize>),
+    /// A word lexed to `EInvalid`.
+    Invalid(Range<usize>),
+}
+
+/// Everything `Tokenizer::tokenize_full` finds in one pass.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenizeResult {
+    pub tokens: Vec<Token>,
+    pub warnings: Vec<Warning>,
+    /// Span of the word each token came from.
+    pub spans: Vec<Range<usize>>,
+}
+
+/// ESIL tokenizer.
+///
+/// `Tokenize::tokenize` uses the default options. Build a `Tokenizer` and
+/// call `tokenize_with` to change them.
+#[derive(Debug, Clone, Default)]
+pub struct Tokenizer {
+    lowercase_identifiers: bool,
+    assume_hex: bool,
+    aliases: HashMap<String, Vec<Token>>,
+    register_offsets: bool,
+    escaped_commas: bool,
+    whitespace_separated: bool,
+    max_identifier_len: Option<usize>,
+    reject_control_chars: bool,
+    inline_interrupts: bool,
+    case_insensitive_keywords: bool,
+    digit_separators: bool,
+}
+
+/// Tokens of `esil` from any lexer `L` whose tokens convert into this
+/// crate's, so that an external lexer can be used with the analyses and
+/// transforms here.
+pub fn tokenize_as<L, T>(esil: T) -> Vec<Token>
+    where L: Tokenize,
+          L::Token: Into<Token>,
+          T: AsRef<str>
+{
+    L::tokenize(esil).into_iter().map(Into::into).collect()
+}
 
 impl Tokenize for Tokenizer {
     type Token = Token;
     fn tokenize<T: AsRef<str>>(esil: T) -> Vec<Self::Token> {
+        Tokenizer::new().tokenize_with(esil)
+    }
+}
+
+impl Tokenizer {
+    pub fn new() -> Tokenizer {
+        Tokenizer::default()
+    }
+
+    /// Whether `s` is an ESIL operator, without tokenizing it. Memory
+    /// operators of any width, eg. `[16]`, are included.
+    pub fn is_operator_str(s: &str) -> bool {
+        static SET: OnceLock<HashSet<&'static str>> = OnceLock::new();
+        SET.get_or_init(|| OPERATORS.iter().cloned().collect()).contains(s) ||
+        sized_memory_op(s).is_some()
+    }
+
+    /// Lowercase identifier names so that eg. `EAX` and `eax` are the same
+    /// register. Operators are not affected.
+    pub fn lowercase_identifiers(mut self, lowercase: bool) -> Tokenizer {
+        self.lowercase_identifiers = lowercase;
+        self
+    }
+
+    /// Parse constants without a `0x` prefix as hex, as found in some legacy
+    /// dumps. Register names made of hex digits (eg. `cf`) then lex as
+    /// constants, so this is off by default.
+    pub fn assume_hex(mut self, assume_hex: bool) -> Tokenizer {
+        self.assume_hex = assume_hex;
+        self
+    }
+
+    /// Expand non-standard `reg+imm` operands such as `eax+4` into
+    /// `4,eax,+`. Off by default.
+    pub fn register_offsets(mut self, register_offsets: bool) -> Tokenizer {
+        self.register_offsets = register_offsets;
+        self
+    }
+
+    /// Treat `\,` as a literal comma inside a token rather than a separator,
+    /// for dialects that allow commas in identifiers. Off by default.
+    pub fn escaped_commas(mut self, escaped_commas: bool) -> Tokenizer {
+        self.escaped_commas = escaped_commas;
+        self
+    }
+
+    /// Also separate tokens by runs of whitespace, for dumps that use spaces
+    /// instead of commas.
+    pub fn whitespace_separated(mut self, whitespace_separated: bool) -> Tokenizer {
+        self.whitespace_separated = whitespace_separated;
+        self
+    }
+
+    /// Turn identifiers longer than `len` bytes into `EInvalid` instead of
+    /// storing them, eg. for fuzzed or corrupt input. Unlimited by default,
+    /// or with `None`.
+    pub fn max_identifier_len(mut self, len: Option<usize>) -> Tokenizer {
+        self.max_identifier_len = len;
+        self
+    }
+
+    /// Turn identifiers containing control characters into `EInvalid`.
+    pub fn reject_control_chars(mut self, reject: bool) -> Tokenizer {
+        self.reject_control_chars = reject;
+        self
+    }
+
+    /// Read `$3` as `3,$`, an interrupt with its number attached, as some
+    /// non-standard ESIL does.
+    pub fn inline_interrupts(mut self, inline_interrupts: bool) -> Tokenizer {
+        self.inline_interrupts = inline_interrupts;
+        self
+    }
+
+    /// Match the textual operators (`POP`, `DUP`, `GOTO`, ...) regardless
+    /// of case, for tools emitting them in lowercase.
+    pub fn case_insensitive_keywords(mut self, case_insensitive: bool) -> Tokenizer {
+        self.case_insensitive_keywords = case_insensitive;
+        self
+    }
+
+    /// Allow underscores between the digits of constants, eg. `0x1234_5678`,
+    /// as found in hand-written ESIL.
+    pub fn digit_separators(mut self, digit_separators: bool) -> Tokenizer {
+        self.digit_separators = digit_separators;
+        self
+    }
+
+    /// Expand `name` into `tokens` wherever it appears, like a built-in
+    /// operator. Built-in operators, constants and internal vars take
+    /// precedence over aliases.
+    pub fn add_alias<T: Into<String>>(&mut self, name: T, tokens: Vec<Token>) {
+        self.aliases.insert(name.into(), tokens);
+    }
+
+    /// Tokenize using this tokenizer's options.
+    pub fn tokenize_with<T: AsRef<str>>(&self, esil: T) -> Vec<Token> {
+        let esil = esil.as_ref().trim();
+        if self.escaped_commas || self.whitespace_separated {
+            let words = self.split_words(esil).into_iter().map(|(_, word)| word);
+            self.expand_operators(words).collect()
+        } else {
+            self.expand_operators(esil.split(',')).collect()
+        }
+    }
+
+    /// Lazily tokenize sub-tokens that were already split on commas, for
+    /// callers doing their own splitting.
+    pub fn expand_operators<'a, I, S>(&'a self, parts: I) -> impl Iterator<Item = Token> + 'a
+        where I: IntoIterator<Item = S>,
+              I::IntoIter: 'a,
+              S: AsRef<str> + 'a
+    {
+        parts.into_iter()
+             .flat_map(move |t| self.lex(iter::once(t.as_ref()), |t| self.identifier(t)))
+    }
+
+    /// Tokenize without allocating identifiers. Every `EIdentifier` borrows
+    /// its name from `esil`, so the default options are always used.
+    pub fn tokenize_borrowed<'a>(esil: &'a str) -> Vec<BorrowedToken<'a>> {
+        Tokenizer::new().lex(esil.trim().split(','), |t| vec![BorrowedToken::EIdentifier(t)])
+    }
+
+    /// Tokenize the ESIL of the instruction at `addr` onto the end of `out`,
+    /// recording `(addr, index of its first token)` in `index`.
+    pub fn tokenize_append<T: AsRef<str>>(&self,
+                                          esil: T,
+                                          addr: u64,
+                                          out: &mut Vec<Token>,
+                                          index: &mut Vec<(u64, usize)>) {
+        index.push((addr, out.len()));
+        out.extend(self.tokenize_with(esil));
+    }
+
+    /// Tokenize the ESIL of each `(address, esil)` instruction on its own,
+    /// keeping the streams separate and tagged with their address.
+    pub fn split_by_instruction<'a, I>(&self, instructions: I) -> Vec<(u64, Vec<Token>)>
+        where I: IntoIterator<Item = (u64, &'a str)>
+    {
+        instructions.into_iter()
+                    .map(|(addr, esil)| (addr, self.tokenize_with(esil)))
+                    .collect()
+    }
+
+    /// Tokenize `esil`, pairing each token with the byte range of the word
+    /// it came from. An escaped comma is part of its word's range.
+    pub fn tokenize_spanned(&self, esil: &str) -> Vec<(Range<usize>, Token)> {
+        self.lex_spanned(esil, trim_range(esil, 0..esil.len()))
+    }
+
+    /// Tokenize `esil`, pairing each token with the word it was expanded
+    /// from, eg. `+=` for each token of its expansion. Escaped commas are
+    /// left escaped in the words.
+    pub fn tokenize_tagged<'a>(&self, esil: &'a str) -> Vec<(Token, &'a str)> {
+        self.tokenize_spanned(esil)
+            .into_iter()
+            .map(|(span, token)| (token, &esil[span]))
+            .collect()
+    }
+
+    /// Tokenize `esil`, returning the span of each token along with
+    /// warnings about suspicious words. The tokens are those of
+    /// `tokenize_with`.
+    pub fn tokenize_full(&self, esil: &str) -> TokenizeResult {
+        let mut result = TokenizeResult::default();
+        for (span, token) in self.tokenize_spanned(esil) {
+            let word = &esil[span.clone()];
+            // Only warn once for the tokens of a word.
+            if result.spans.last() != Some(&span) {
+                match token {
+                    Token::EInvalid => result.warnings.push(Warning::Invalid(span.clone())),
+                    Token::EIdentifier(_) if word.starts_with(|c: char| c.is_ascii_digit()) => {
+                        result.warnings.push(Warning::MalformedConstant(span.clone()));
+                    }
+                    _ => { }
+                }
+            }
+            result.tokens.push(token);
+            result.spans.push(span);
+        }
+        result
+    }
+
+    /// Tokens of `esil` with `edit` replaced by `replacement`, given `old`,
+    /// the tokens of `esil` from `tokenize_spanned`.
+    ///
+    /// Only the words touched by the edit are tokenized again. Tokens of the
+    /// other words are reused, with their spans shifted to match the edited
+    /// string, so the result is the same as `tokenize_spanned` on it. With
+    /// escaped commas or whitespace separators, where words are not simply
+    /// delimited by commas, the whole edited string is tokenized again.
+    pub fn retokenize(&self,
+                      esil: &str,
+                      old: &[(Range<usize>, Token)],
+                      edit: Range<usize>,
+                      replacement: &str) -> Vec<(Range<usize>, Token)> {
+        if self.escaped_commas || self.whitespace_separated {
+            let edited = format!("{}{}{}", &esil[..edit.start], replacement, &esil[edit.end..]);
+            return self.tokenize_spanned(&edited);
+        }
+        let start = esil[..edit.start].rfind(',').map_or(0, |i| i + 1);
+        let end = esil[edit.end..].find(',').map_or(esil.len(), |i| edit.end + i);
+        let edited = format!("{}{}{}", &esil[..edit.start], replacement, &esil[edit.end..]);
+        let shift = |pos: usize| pos - edit.len() + replacement.len();
+        let mut tokens: Vec<_> = old.iter()
+                                    .filter(|t| t.0.end < start)
+                                    .cloned()
+                                    .collect();
+        tokens.extend(self.lex_spanned(&edited, trim_range(&edited, start..shift(end))));
+        tokens.extend(old.iter()
+                         .filter(|t| t.0.start > end)
+                         .map(|t| (shift(t.0.start)..shift(t.0.end), t.1.clone())));
+        tokens
+    }
+
+    // Tokens of the words in `esil[range]`, with their spans.
+    fn lex_spanned(&self, esil: &str, range: Range<usize>) -> Vec<(Range<usize>, Token)> {
         let mut tokens = Vec::new();
-        for t in esil.as_ref().split(",").into_iter() {
-            tokens.extend(
+        for (span, word) in self.split_words(&esil[range.clone()]) {
+            let span = range.start + span.start..range.start + span.end;
+            tokens.extend(self.expand_operators(iter::once(word)).map(|t| (span.clone(), t)));
+        }
+        tokens
+    }
+
+    // The words of `esil` as separated with this tokenizer's options, with
+    // their byte ranges. Escaped commas are unescaped in the words.
+    fn split_words<'a>(&self, esil: &'a str) -> Vec<(Range<usize>, Cow<'a, str>)> {
+        let mut words = Vec::new();
+        let mut start = 0;
+        let mut escaped = false;
+        let mut push = |range: Range<usize>, escaped: bool| {
+            let word = &esil[range.clone()];
+            if !(self.whitespace_separated && word.is_empty()) {
+                let word = if escaped { Cow::Owned(word.replace("\\,", ",")) } else { word.into() };
+                words.push((range, word));
+            }
+        };
+        let mut chars = esil.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if self.escaped_commas && c == '\\' && chars.peek().map(|&(_, c)| c) == Some(',') {
+                chars.next();
+                escaped = true;
+            } else if c == ',' || (self.whitespace_separated && c.is_whitespace()) {
+                push(start..i, escaped);
+                start = i + c.len_utf8();
+                escaped = false;
+            }
+        }
+        push(start..esil.len(), escaped);
+        words
+    }
+
+    // Tokens for a name that is not an operator, constant or internal var.
+    fn identifier(&self, t: &str) -> Vec<Token> {
+        if let Some(tokens) = self.aliases.get(t) {
+            return tokens.clone();
+        }
+        if self.max_identifier_len.is_some_and(|len| t.len() > len) ||
+           (self.reject_control_chars && t.chars().any(char::is_control)) {
+            return vec![Token::EInvalid];
+        }
+        if let (true, Some((reg, imm))) = (self.register_offsets, t.split_once('+')) {
+            match self.parse_constant(imm) {
+                Some(imm) if !reg.is_empty() => {
+                    let mut tokens = vec![Token::EConstant(imm)];
+                    tokens.extend(self.identifier(reg));
+                    tokens.push(Token::EAdd);
+                    return tokens;
+                }
+                _ => { }
+            }
+        }
+        if self.lowercase_identifiers {
+            vec![Token::EIdentifier(t.to_lowercase())]
+        } else {
+            vec![Token::EIdentifier(t.to_owned())]
+        }
+    }
+
+    // Number of an interrupt written as `$<number>`, if enabled.
+    fn inline_interrupt(&self, t: &str) -> Option<u64> {
+        t.strip_prefix(ESIL_INTERNAL_PREFIX)
+         .filter(|n| self.inline_interrupts && n.starts_with(|c: char| c.is_ascii_digit()))
+         .and_then(|n| self.parse_constant(n))
+    }
+
+    fn parse_constant(&self, t: &str) -> Option<u64> {
+        if self.digit_separators && t.contains('_') && t.starts_with(|c: char| c.is_ascii_digit()) {
+            return self.parse_constant(&t.replace('_', ""));
+        }
+        if let Some(hex) = t.strip_prefix("0x") {
+            Num::from_str_radix(hex, 16).ok()
+        } else if self.assume_hex {
+            Num::from_str_radix(t, 16).ok()
+        } else {
+            t.parse::<u64>().ok()
+        }
+    }
+
+    fn lex<'a, I, S, F>(&self, parts: I, mut identifier: F) -> Vec<GenericToken<S>>
+        where I: Iterator<Item = &'a str>,
+              F: FnMut(&'a str) -> Vec<GenericToken<S>>
+    {
+        // Shared by owned and borrowed tokens, which differ in how
+        // identifiers are built.
+        use self::GenericToken as Token;
+        let mut tokens = Vec::new();
+        for t in parts {
+            let t = if self.case_insensitive_keywords { keyword(t).unwrap_or(t) } else { t };
+            // Memory operators at widths without an arm of their own, eg.
+            // `[16]`, are lexed as their `[]` form at that width.
+            let (t, width) = match sized_memory_op(t) {
+                Some((op, bits)) => (op, Some(bits)),
+                None => (t, None),
+            };
+            let mut expansion =
                 match t {
                     "$" => vec![Token::EInterrupt],
                     "==" => vec![Token::ECmp],
                     "<" => vec![Token::ELt],
                     ">" => vec![Token::EGt],
-                    "<=" => vec![Token::PCopy(2), Token::ELt, Token::PPop(2),
-                    Token::ECmp, Token::EOr],
-                    ">=" => vec![Token::PCopy(2), Token::EGt, Token::PPop(2),
-                    Token::ECmp, Token::EOr],
+                    // `==` only sets flags, so these are the negation of
+                    // the opposite comparison.
+                    "<=" => vec![Token::EGt, Token::ENeg],
+                    ">=" => vec![Token::ELt, Token::ENeg],
                     "?{" => vec![Token::EIf],
                     "<<" => vec![Token::ELsl],
-                    "<<=" => vec![Token::PCopy(2), Token::ELsl, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    // Compound assignments keep a copy of the destination,
+                    // apply the operation and restore the destination for
+                    // `=`. Binary operators take the top of the stack as
+                    // their first operand, so a decrement slips the `1`
+                    // underneath the value being decremented.
+                    "<<=" => vec![Token::PCopy(1), Token::ELsl, Token::PPop(1),
+                    Token::EEq],
                     ">>" => vec![Token::ELsr],
-                    ">>=" => vec![Token::PCopy(2), Token::ELsr, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    ">>=" => vec![Token::PCopy(1), Token::ELsr, Token::PPop(1),
+                    Token::EEq],
                     ">>>" => vec![Token::ERor],
                     "<<<" => vec![Token::ERol],
                     "&" => vec![Token::EAnd],
-                    "&=" => vec![Token::PCopy(2), Token::EAnd, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    "&=" => vec![Token::PCopy(1), Token::EAnd, Token::PPop(1),
+                    Token::EEq],
                     "}" => vec![Token::ENop],
                     "|" => vec![Token::EOr],
-                    "|=" => vec![Token::PCopy(2), Token::EOr, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    "|=" => vec![Token::PCopy(1), Token::EOr, Token::PPop(1),
+                    Token::EEq],
                     "!" => vec![Token::ENeg],
-                    "!=" => vec![Token::PCopy(1), Token::ENeg, Token::EEq],
+                    "!=" => vec![Token::PCopy(1), Token::ENeg, Token::PPop(1), Token::EEq],
                     "=" => vec![Token::EEq],
+                    ":=" => vec![Token::EWeakEq],
                     "*" => vec![Token::EMul],
-                    "*=" => vec![Token::PCopy(2), Token::EMul, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    "L*" => vec![Token::ELongMul],
+                    "*=" => vec![Token::PCopy(1), Token::EMul, Token::PPop(1),
+                    Token::EEq],
                     "^" => vec![Token::EXor],
-                    "^=" => vec![Token::PCopy(2), Token::EXor, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    "^=" => vec![Token::PCopy(1), Token::EXor, Token::PPop(1),
+                    Token::EEq],
                     "+" => vec![Token::EAdd],
-                    "+=" => vec![Token::PCopy(2), Token::EAdd, Token::PPop(1),
-                    Token::EPop, Token::EEq],
-                    "++" => vec![Token::PPop(1), Token::EConstant(1), Token::EAdd],
+                    "+=" => vec![Token::PCopy(1), Token::EAdd, Token::PPop(1),
+                    Token::EEq],
+                    "++" => vec![Token::EConstant(1), Token::EAdd],
                     "++=" => vec![Token::PCopy(1), Token::EConstant(1), Token::EAdd,
                     Token::PPop(1), Token::EEq],
                     "-" => vec![Token::ESub],
-                    "-=" => vec![Token::PCopy(2), Token::ESub, Token::PPop(1),
-                    Token::EPop, Token::EEq],
-                    "--" => vec![Token::PPop(1), Token::EConstant(1), Token::ESub],
-                    "--=" => vec![Token::PCopy(1), Token::EConstant(1), Token::ESub,
+                    "-=" => vec![Token::PCopy(1), Token::ESub, Token::PPop(1),
+                    Token::EEq],
+                    "--" => vec![Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub],
+                    "--=" => vec![Token::PCopy(1),
+                    Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub,
                     Token::PPop(1), Token::EEq],
                     "/" => vec![Token::EDiv],
-                    "/=" => vec![Token::PCopy(2), Token::EDiv, Token::PPop(1),
-                    Token::EPop, Token::EEq],
+                    "/=" => vec![Token::PCopy(1), Token::EDiv, Token::PPop(1),
+                    Token::EEq],
                     "%" => vec![Token::EMod],
-                    "%=" => vec![Token::PCopy(2), Token::EMod, Token::PPop(1),
-                    Token::EPop, Token::EEq],
-                    "=[]" => vec![Token::EPoke(64)],
+                    "%=" => vec![Token::PCopy(1), Token::EMod, Token::PPop(1),
+                    Token::EEq],
+                    "=[]" => vec![Token::EPoke(0)],
                     "=[1]" => vec![Token::EPoke(8)],
                     "=[2]" => vec![Token::EPoke(16)],
                     "=[4]" => vec![Token::EPoke(32)],
                     "=[8]" => vec![Token::EPoke(64)],
-                    "|=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EOr,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    "|=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EOr,
+                    Token::PPop(1), Token::EPoke(0)],
                     "|=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EOr,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "|=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EOr,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "|=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EOr,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "|=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EOr,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "^=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EXor,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "^=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EXor,
+                    Token::PPop(1), Token::EPoke(0)],
                     "^=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EXor,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "^=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EXor,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "^=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EXor,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "^=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EXor,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "&=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EAnd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "&=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EAnd,
+                    Token::PPop(1), Token::EPoke(0)],
                     "&=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EAnd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "&=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EAnd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "&=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EAnd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "&=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EAnd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "+=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EAdd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "+=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EAdd,
+                    Token::PPop(1), Token::EPoke(0)],
                     "+=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EAdd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "+=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EAdd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "+=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EAdd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "+=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EAdd,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "-=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::ESub,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "-=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::ESub,
+                    Token::PPop(1), Token::EPoke(0)],
                     "-=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::ESub,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "-=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::ESub,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "-=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::ESub,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "-=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::ESub,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "%=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EMod,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "%=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EMod,
+                    Token::PPop(1), Token::EPoke(0)],
                     "%=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EMod,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "%=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EMod,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "%=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EMod,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "%=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EMod,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "/=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EDiv,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "/=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EDiv,
+                    Token::PPop(1), Token::EPoke(0)],
                     "/=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EDiv,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "/=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EDiv,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "/=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EDiv,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "/=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EDiv,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "*=[]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EMul,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
+                    Token::PPop(1), Token::EPoke(64)],
+                    "*=[]" => vec![Token::PCopy(1), Token::EPeek(0), Token::EMul,
+                    Token::PPop(1), Token::EPoke(0)],
                     "*=[1]" => vec![Token::PCopy(1), Token::EPeek(8), Token::EMul,
-                    Token::PPop(1), Token::EPop, Token::EPoke(8)],
+                    Token::PPop(1), Token::EPoke(8)],
                     "*=[2]" => vec![Token::PCopy(1), Token::EPeek(16), Token::EMul,
-                    Token::PPop(1), Token::EPop, Token::EPoke(16)],
+                    Token::PPop(1), Token::EPoke(16)],
                     "*=[4]" => vec![Token::PCopy(1), Token::EPeek(32), Token::EMul,
-                    Token::PPop(1), Token::EPop, Token::EPoke(32)],
+                    Token::PPop(1), Token::EPoke(32)],
                     "*=[8]" => vec![Token::PCopy(1), Token::EPeek(64), Token::EMul,
-                    Token::PPop(1), Token::EPop, Token::EPoke(64)],
-                    "++=[]" => vec![Token::PCopy(1), Token::EPeek(64),
-                    Token::EConstant(1), Token::EAdd,
                     Token::PPop(1), Token::EPoke(64)],
+                    // Read-modify-write of memory: keep a copy of the
+                    // address, peek, apply the operation to the loaded
+                    // value, restore the address and poke. The decrement
+                    // slips the `1` underneath the loaded value since `-`
+                    // subtracts the second value from the top one.
+                    "++=[]" => vec![Token::PCopy(1), Token::EPeek(0),
+                    Token::EConstant(1), Token::EAdd,
+                    Token::PPop(1), Token::EPoke(0)],
                     "++=[1]" => vec![Token::PCopy(1), Token::EPeek(8),
                     Token::EConstant(1), Token::EAdd,
                     Token::PPop(1), Token::EPoke(8)],
@@ -222,29 +960,29 @@ impl Tokenize for Tokenizer {
                     "++=[8]" => vec![Token::PCopy(1), Token::EPeek(64),
                     Token::EConstant(1), Token::EAdd,
                     Token::PPop(1), Token::EPoke(64)],
-                    "--=[]" => vec![Token::EConstant(1), Token::PPop(1),
-                    Token::PCopy(1), Token::EPeek(64),
-                    Token::ESub, Token::PPop(1),
-                    Token::EPoke(64)],
-                    "--=[1]" => vec![Token::EConstant(1), Token::PPop(1),
-                    Token::PCopy(1), Token::EPeek(8),
-                    Token::ESub, Token::PPop(1),
-                    Token::EPoke(8)],
-                    "--=[2]" => vec![Token::EConstant(1), Token::PPop(1),
-                    Token::PCopy(1), Token::EPeek(16),
-                    Token::ESub, Token::PPop(1),
-                    Token::EPoke(16)],
-                    "--=[4]" => vec![Token::EConstant(1), Token::PPop(1),
-                    Token::PCopy(1), Token::EPeek(32),
-                    Token::ESub, Token::PPop(1),
-                    Token::EPoke(32)],
-                    "--=[8]" => vec![Token::EConstant(1), Token::PPop(1),
-                    Token::PCopy(1), Token::EPeek(64),
-                    Token::ESub, Token::PPop(1),
-                    Token::EPoke(64)],
-                    "[]" => vec![Token::EPeek(64)],
-                    "[*]" => vec![Token::EPeek(64)],
-                    "=[*]" => vec![Token::EPoke(64)],
+                    "--=[]" => vec![Token::PCopy(1), Token::EPeek(0),
+                    Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub,
+                    Token::PPop(1), Token::EPoke(0)],
+                    "--=[1]" => vec![Token::PCopy(1), Token::EPeek(8),
+                    Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub,
+                    Token::PPop(1), Token::EPoke(8)],
+                    "--=[2]" => vec![Token::PCopy(1), Token::EPeek(16),
+                    Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub,
+                    Token::PPop(1), Token::EPoke(16)],
+                    "--=[4]" => vec![Token::PCopy(1), Token::EPeek(32),
+                    Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub,
+                    Token::PPop(1), Token::EPoke(32)],
+                    "--=[8]" => vec![Token::PCopy(1), Token::EPeek(64),
+                    Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                    Token::PPop(1), Token::ESub,
+                    Token::PPop(1), Token::EPoke(64)],
+                    "[]" => vec![Token::EPeek(0)],
+                    "[*]" => vec![Token::EPeek(0)],
+                    "=[*]" => vec![Token::EPoke(0)],
                     "[1]" => vec![Token::EPeek(8)],
                     "[2]" => vec![Token::EPeek(16)],
                     "[4]" => vec![Token::EPeek(32)],
@@ -253,52 +991,525 @@ impl Tokenize for Tokenizer {
                     "POP" => vec![Token::EPop],
                     "TODO" => vec![Token::ETodo],
                     "GOTO" => vec![Token::EGoto],
+                    "REPEAT" => vec![Token::ERepeat],
                     "BREAK" => vec![Token::EBreak],
                     "CLEAR" => vec![Token::EClear],
                     "DUP" => vec![Token::EDup],
                     "TRAP" => vec![Token::ETrap],
+                    "PICK" => vec![Token::EPick],
+                    "RPICK" => vec![Token::ERpick],
                     _   => {
-                        // Handle internal vars
-                        if Some(ESIL_INTERNAL_PREFIX) == t.chars().nth(0) {
-                            let bit = if t.len() < 3 {
-                                0
+                        if let Some(n) = self.inline_interrupt(t) {
+                            vec![Token::EConstant(n), Token::EInterrupt]
+                        } else if let Some(var) = t.strip_prefix(ESIL_INTERNAL_PREFIX) {
+                            // Handle internal vars
+                            let mut chars = var.chars();
+                            let name = chars.next().unwrap_or('\0');
+                            let suffix = chars.as_str();
+                            // A suffix that is not a bit number, eg. because
+                            // it is out of range, makes the var invalid
+                            // rather than being dropped.
+                            let bit = if suffix.is_empty() {
+                                Some(None)
+                            } else if suffix.bytes().all(|b| b.is_ascii_digit()) {
+                                suffix.parse::<u8>().ok().map(Some)
                             } else {
-                                t[2..].parse::<u8>().unwrap_or(0)
+                                None
                             };
-                            match t.chars().nth(1).unwrap_or('\0') {
-                                '$' => vec![Token::IAddress(bit)],
-                                'z' => vec![Token::IZero(bit)],
-                                'b' => vec![Token::IBorrow(bit)],
-                                'c' => vec![Token::ICarry(bit)],
-                                'p' => vec![Token::IParity(bit)],
-                                'r' => vec![Token::ISize(bit)],
-                                'o' => vec![Token::IOverflow(bit)],
-                                's' => vec![Token::ISign(bit)],
+                            match (name, bit) {
+                                (_, None) => vec![Token::EInvalid],
+                                ('$', Some(bit)) => vec![Token::IAddress(bit)],
+                                ('z', Some(bit)) => vec![Token::IZero(bit)],
+                                ('b', Some(bit)) => vec![Token::IBorrow(bit)],
+                                ('c', Some(bit)) => vec![Token::ICarry(bit)],
+                                ('p', Some(bit)) => vec![Token::IParity(bit)],
+                                ('r', Some(bit)) => vec![Token::ISize(bit)],
+                                ('o', Some(bit)) => vec![Token::IOverflow(bit)],
+                                ('s', Some(bit)) => vec![Token::ISign(bit)],
                                 _ => vec![Token::EInvalid],
                             }
-                        } else if let Ok(v) = Num::from_str_radix(t.trim_left_matches("0x"), 16) {
-                            vec![Token::EConstant(v)]
-                        } else if let Ok(v) = t.parse::<u64>() {
+                        } else if let Some(v) = self.parse_constant(t) {
                             vec![Token::EConstant(v)]
+                        } else if let Some(n) = t.strip_prefix('-') {
+                            // A leading `-` on a number is a negative constant,
+                            // stored in two's complement. `-` followed by
+                            // anything else (eg. `-eax`) is not valid ESIL.
+                            match self.parse_constant(n) {
+                                Some(v) => vec![Token::EConstant(v.wrapping_neg())],
+                                None => vec![Token::EInvalid],
+                            }
                         } else {
                             // Just returns it as an identifier. It is upto the
                             // parser to decide if it is a valid token.
-                            vec![Token::EIdentifier(t.to_owned())]
+                            identifier(t)
                         }
                     }
-                });
+                };
+            if let Some(bits) = width {
+                for token in &mut expansion {
+                    match *token {
+                        Token::EPeek(ref mut w) | Token::EPoke(ref mut w) => *w = bits,
+                        _ => { }
+                    }
+                }
+            }
+            tokens.extend(expansion);
         }
         tokens
     }
 }
 
+
+// The textual operator `t` spells in any case, eg. `DUP` for `dup`.
+fn keyword(t: &str) -> Option<&'static str> {
+    OPERATORS.iter()
+             .find(|op| op.bytes().all(|b| b.is_ascii_uppercase()) && op.eq_ignore_ascii_case(t))
+             .cloned()
+}
+
+// `<op>[n]` for a byte count `n` the lexer has no arm for, as the `<op>[]`
+// operator and the width in bits.
+fn sized_memory_op(t: &str) -> Option<(&'static str, u16)> {
+    let (prefix, n) = t.strip_suffix(']')?.rsplit_once('[')?;
+    if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) || OPERATORS.contains(&t) {
+        return None;
+    }
+    let bits = n.parse::<u16>().ok()?.checked_mul(8).filter(|&bits| bits > 0)?;
+    let op = OPERATORS.iter().find(|op| op.strip_suffix("[]") == Some(prefix))?;
+    Some((op, bits))
+}
+
+// `range` without the whitespace at the very start and end of `esil`, which
+// tokenizing trims.
+fn trim_range(esil: &str, range: Range<usize>) -> Range<usize> {
+    let start = cmp::max(range.start, esil.len() - esil.trim_start().len());
+    let end = cmp::min(range.end, esil.trim_end().len());
+    start..cmp::max(start, end)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
     fn esil_basic() {
-        let op = vec![Token::EAdd];
+        let op = [Token::EAdd];
         assert_eq!(op[0], Tokenizer::tokenize("+")[0]);
     }
+
+    #[test]
+    fn esil_operators() {
+        for op in OPERATORS {
+            let tokens = Tokenizer::tokenize(op);
+            assert!(!tokens.is_empty());
+            assert!(tokens.iter().all(|t| !matches!(*t, Token::EIdentifier(_) | Token::EInvalid)),
+                    "{} is not an operator", op);
+        }
+    }
+
+    #[test]
+    fn esil_leading_minus() {
+        assert_eq!(Tokenizer::tokenize("-"), vec![Token::ESub]);
+        assert_eq!(Tokenizer::tokenize("-1"), vec![Token::EConstant(!0)]);
+        assert_eq!(Tokenizer::tokenize("-0x10"),
+                   vec![Token::EConstant(0u64.wrapping_sub(0x10))]);
+        assert_eq!(Tokenizer::tokenize("-eax"), vec![Token::EInvalid]);
+    }
+
+    #[test]
+    fn esil_borrowed_identifiers() {
+        let esil = "4,eax,+";
+        let tokens = Tokenizer::tokenize_borrowed(esil);
+        assert_eq!(tokens, vec![BorrowedToken::EConstant(4),
+                                BorrowedToken::EIdentifier("eax"),
+                                BorrowedToken::EAdd]);
+        if let BorrowedToken::EIdentifier(name) = tokens[1] {
+            assert_eq!(name.as_ptr(), esil[2..].as_ptr());
+        }
+    }
+
+    #[test]
+    fn esil_internal_var_bad_suffix() {
+        assert_eq!(Tokenizer::tokenize("$z999999999999999"), vec![Token::EInvalid]);
+        assert_eq!(Tokenizer::tokenize("$z256"), vec![Token::EInvalid]);
+        assert_eq!(Tokenizer::tokenize("$zx"), vec![Token::EInvalid]);
+        assert_eq!(Tokenizer::tokenize("$z255"), vec![Token::IZero(Some(255))]);
+        let long = format!("$z{}", "9".repeat(100_000));
+        assert_eq!(Tokenizer::tokenize(long), vec![Token::EInvalid]);
+    }
+
+    #[test]
+    fn esil_clear() {
+        assert_eq!(Tokenizer::tokenize("1,2,CLEAR"),
+                   vec![Token::EConstant(1), Token::EConstant(2), Token::EClear]);
+    }
+
+    #[test]
+    fn esil_address_arithmetic() {
+        assert_eq!(Tokenizer::tokenize("$$,4,+"),
+                   vec![Token::IAddress(None), Token::EConstant(4), Token::EAdd]);
+    }
+
+    #[test]
+    fn esil_arity() {
+        assert_eq!(Token::EAdd.arity(), 2);
+        assert_eq!(Token::ECmp.arity(), 2);
+        assert_eq!(Token::ENeg.arity(), 1);
+        assert_eq!(Token::EPeek(8).arity(), 1);
+        assert_eq!(Token::EPoke(8).arity(), 2);
+        assert_eq!(Token::EConstant(4).arity(), 0);
+    }
+
+    #[test]
+    fn esil_rewrite_constant() {
+        let mut tokens = Tokenizer::tokenize("0x1000,eax,=");
+        for token in &mut tokens {
+            if token.as_constant() == Some(0x1000) {
+                assert!(token.set_constant(0x2000));
+            }
+        }
+        assert_eq!(tokens, Tokenizer::tokenize("0x2000,eax,="));
+        assert_eq!(tokens[1].as_constant(), None);
+        assert!(!tokens[1].set_constant(0));
+    }
+
+    #[test]
+    fn esil_kind() {
+        assert_eq!(Token::EAdd.kind(), TokenKind::Arithmetic);
+        assert_eq!(Token::EPeek(8).kind(), TokenKind::Memory);
+        assert_eq!(Token::ICarry(None).kind(), TokenKind::InternalVar);
+    }
+
+    #[test]
+    fn esil_terminator() {
+        assert!(Token::EGoto.is_terminator());
+        assert!(Token::EIf.is_terminator());
+        assert!(!Token::EAdd.is_terminator());
+    }
+
+    #[test]
+    fn esil_repeat() {
+        assert_eq!(Tokenizer::tokenize("3,0,REPEAT"),
+                   vec![Token::EConstant(3), Token::EConstant(0), Token::ERepeat]);
+        assert_eq!(Token::ERepeat.stack_effect(), (2, 0));
+        assert!(Token::ERepeat.is_terminator());
+        assert_eq!(Token::ERepeat.side_effect(), SideEffect::Control);
+    }
+
+    #[test]
+    fn esil_side_effect() {
+        assert_eq!(Token::EPoke(8).side_effect(), SideEffect::MemoryWrite);
+        assert_eq!(Token::EEq.side_effect(), SideEffect::Store);
+        assert_eq!(Token::EWeakEq.side_effect(), SideEffect::Store);
+        assert_eq!(Token::EAdd.side_effect(), SideEffect::Pure);
+        assert_eq!(Token::EGoto.side_effect(), SideEffect::Control);
+    }
+
+    #[test]
+    fn esil_surrounding_whitespace() {
+        assert_eq!(Tokenizer::tokenize("  4,eax,+  "), Tokenizer::tokenize("4,eax,+"));
+    }
+
+    #[test]
+    fn esil_constants() {
+        assert_eq!(Tokenizer::tokenize("0x10,10"),
+                   vec![Token::EConstant(16), Token::EConstant(10)]);
+        assert_eq!(Tokenizer::tokenize("ff"), vec![Token::EIdentifier("ff".to_owned())]);
+        assert_eq!(Tokenizer::tokenize("cf"), vec![Token::EIdentifier("cf".to_owned())]);
+    }
+
+    #[test]
+    fn esil_assume_hex() {
+        let tokenizer = Tokenizer::new().assume_hex(true);
+        assert_eq!(tokenizer.tokenize_with("ff,0x10,eax"),
+                   vec![Token::EConstant(0xff), Token::EConstant(0x10),
+                        Token::EIdentifier("eax".to_owned())]);
+    }
+
+    #[test]
+    fn esil_lowercase_identifiers() {
+        let tokenizer = Tokenizer::new().lowercase_identifiers(true);
+        assert_eq!(tokenizer.tokenize_with("EAX,POP"),
+                   vec![Token::EIdentifier("eax".to_owned()), Token::EPop]);
+        assert_eq!(Tokenizer::tokenize("EAX"),
+                   vec![Token::EIdentifier("EAX".to_owned())]);
+    }
+
+    #[test]
+    fn esil_register_offsets() {
+        let tokenizer = Tokenizer::new().register_offsets(true);
+        assert_eq!(tokenizer.tokenize_with("eax+4,[4]"),
+                   vec![Token::EConstant(4), Token::EIdentifier("eax".to_owned()),
+                        Token::EAdd, Token::EPeek(32)]);
+        assert_eq!(tokenizer.tokenize_with("eax+ebx"),
+                   vec![Token::EIdentifier("eax+ebx".to_owned())]);
+        assert_eq!(Tokenizer::tokenize("eax+4"),
+                   vec![Token::EIdentifier("eax+4".to_owned())]);
+    }
+
+    #[test]
+    fn esil_escaped_commas() {
+        let tokenizer = Tokenizer::new().escaped_commas(true);
+        assert_eq!(tokenizer.tokenize_with("foo\\,bar,1,+"),
+                   vec![Token::EIdentifier("foo,bar".to_owned()),
+                        Token::EConstant(1), Token::EAdd]);
+        let result = tokenizer.tokenize_full("foo\\,bar,1,+");
+        assert_eq!(result.tokens, tokenizer.tokenize_with("foo\\,bar,1,+"));
+        assert_eq!(result.spans, vec![0..8, 9..10, 11..12]);
+        let spanned = Tokenizer::new().whitespace_separated(true).tokenize_spanned(" eax  1,+ ");
+        assert_eq!(spanned.iter().map(|t| t.0.clone()).collect::<Vec<_>>(),
+                   vec![1..4, 6..7, 8..9]);
+        assert_eq!(Tokenizer::tokenize("foo\\,bar"),
+                   vec![Token::EIdentifier("foo\\".to_owned()),
+                        Token::EIdentifier("bar".to_owned())]);
+    }
+
+    #[test]
+    fn esil_long_mul() {
+        assert_eq!(Tokenizer::tokenize("eax,ebx,L*"),
+                   vec![Token::EIdentifier("eax".to_owned()), Token::EIdentifier("ebx".to_owned()),
+                        Token::ELongMul]);
+        assert_eq!(Token::ELongMul.stack_effect(), (2, 2));
+        assert_eq!(Token::ELongMul.kind(), TokenKind::Arithmetic);
+    }
+
+    #[test]
+    fn esil_weak_assign() {
+        assert_eq!(Tokenizer::tokenize("1,eax,:="),
+                   vec![Token::EConstant(1), Token::EIdentifier("eax".to_owned()), Token::EWeakEq]);
+        assert_eq!(Token::EWeakEq.kind(), Token::EEq.kind());
+        assert!(Token::EWeakEq.describe().contains("without updating flags"));
+    }
+
+    #[test]
+    fn esil_pick() {
+        assert_eq!(Tokenizer::tokenize("1,PICK,0,RPICK"),
+                   vec![Token::EConstant(1), Token::EPick, Token::EConstant(0), Token::ERpick]);
+        assert_eq!(Token::EPick.stack_effect(), (1, 1));
+    }
+
+    #[test]
+    fn esil_expand_operators() {
+        let tokens: Vec<_> = Tokenizer::new().expand_operators(vec!["4", "5", "+="]).collect();
+        assert_eq!(tokens, Tokenizer::tokenize("4,5,+="));
+        assert_eq!(tokens[2..], [Token::PCopy(1), Token::EAdd, Token::PPop(1), Token::EEq]);
+    }
+
+    #[test]
+    fn esil_identifier_guards() {
+        let tokenizer = Tokenizer::new().max_identifier_len(Some(3));
+        assert_eq!(tokenizer.tokenize_with("eax,rflags,="),
+                   vec![Token::EIdentifier("eax".to_owned()), Token::EInvalid, Token::EEq]);
+        let tokenizer = tokenizer.max_identifier_len(None);
+        assert_eq!(tokenizer.tokenize_with("rflags"),
+                   vec![Token::EIdentifier("rflags".to_owned())]);
+        let tokenizer = Tokenizer::new().reject_control_chars(true);
+        assert_eq!(tokenizer.tokenize_with("e\x01ax"), vec![Token::EInvalid]);
+        assert_eq!(Tokenizer::tokenize("e\x01ax"),
+                   vec![Token::EIdentifier("e\x01ax".to_owned())]);
+    }
+
+    #[test]
+    fn esil_whitespace_separated() {
+        let tokenizer = Tokenizer::new().whitespace_separated(true);
+        let expected = vec![Token::EConstant(4), Token::EIdentifier("eax".to_owned()), Token::EAdd];
+        assert_eq!(tokenizer.tokenize_with("4 eax +"), expected);
+        assert_eq!(tokenizer.tokenize_with("4,  eax\t+"), expected);
+        let tokenizer = tokenizer.escaped_commas(true);
+        assert_eq!(tokenizer.tokenize_with("4 a\\,b +")[1], Token::EIdentifier("a,b".to_owned()));
+        assert_eq!(Tokenizer::tokenize("4 eax +"), vec![Token::EIdentifier("4 eax +".to_owned())]);
+    }
+
+    #[test]
+    fn esil_describe() {
+        assert_eq!(Token::EPoke(8).describe(), "store 1 byte to memory (pop addr, pop value)");
+        assert!(Token::EPoke(0).describe().contains("a word"));
+        assert!(Token::EPeek(32).describe().contains("4 bytes"));
+        assert!(Token::ESub.describe().contains("pop a, pop b, push a - b"));
+    }
+
+    #[test]
+    fn esil_spans() {
+        let tokenizer = Tokenizer::new();
+        let tokens = tokenizer.tokenize_spanned(" 4,eax,+= ");
+        assert_eq!(tokens[1], (3..6, Token::EIdentifier("eax".to_owned())));
+        assert!(tokens[2..].iter().all(|t| t.0 == (7..9)));
+        assert_eq!(tokens.into_iter().map(|(_, t)| t).collect::<Vec<_>>(),
+                   Tokenizer::tokenize(" 4,eax,+= "));
+    }
+
+    #[test]
+    fn esil_tokenize_full() {
+        let result = Tokenizer::new().tokenize_full("0xzz,eax,+=,-ebx");
+        assert_eq!(result.tokens, Tokenizer::tokenize("0xzz,eax,+=,-ebx"));
+        assert_eq!(result.spans.len(), result.tokens.len());
+        assert_eq!(result.spans[1], 5..8);
+        assert_eq!(result.warnings,
+                   vec![Warning::MalformedConstant(0..4), Warning::Invalid(12..16)]);
+    }
+
+    #[test]
+    fn esil_tokenize_tagged() {
+        let tokens = Tokenizer::new().tokenize_tagged("4,eax,+=");
+        assert_eq!(tokens[1], (Token::EIdentifier("eax".to_owned()), "eax"));
+        assert_eq!(tokens.len(), 6);
+        assert!(tokens[2..].iter().all(|&(_, tag)| tag == "+="));
+    }
+
+    #[test]
+    fn esil_retokenize() {
+        let tokenizer = Tokenizer::new();
+        let esil = "4,eax,+,ebx,=";
+        let old = tokenizer.tokenize_spanned(esil);
+        let new = tokenizer.retokenize(esil, &old, 2..5, "ecx");
+        assert_eq!(new, tokenizer.tokenize_spanned("4,ecx,+,ebx,="));
+        let changed: Vec<_> = (0..old.len()).filter(|&i| old[i] != new[i]).collect();
+        assert_eq!(changed, vec![1]);
+        let new = tokenizer.retokenize(esil, &old, 6..7, "+=");
+        assert_eq!(new, tokenizer.tokenize_spanned("4,eax,+=,ebx,="));
+        assert_eq!(new[0..2], old[0..2]);
+        let tokenizer = Tokenizer::new().escaped_commas(true);
+        let old = tokenizer.tokenize_spanned("a\\,b,1,+");
+        assert_eq!(tokenizer.retokenize("a\\,b,1,+", &old, 3..4, "c"),
+                   tokenizer.tokenize_spanned("a\\,c,1,+"));
+    }
+
+    #[test]
+    fn esil_wide_memory() {
+        assert_eq!(Tokenizer::tokenize("rsp,[16]"),
+                   vec![Token::EIdentifier("rsp".to_owned()), Token::EPeek(128)]);
+        assert_eq!(Tokenizer::tokenize("xmm0,rsp,=[32]")[2], Token::EPoke(256));
+        assert_eq!(Tokenizer::tokenize("1,rsp,+=[16]")[2..],
+                   [Token::PCopy(1), Token::EPeek(128), Token::EAdd,
+                    Token::PPop(1), Token::EPoke(128)]);
+        assert_eq!(Tokenizer::tokenize("[0]"), vec![Token::EIdentifier("[0]".to_owned())]);
+        assert_eq!(Tokenizer::tokenize("[8192]"), vec![Token::EIdentifier("[8192]".to_owned())]);
+    }
+
+    #[test]
+    fn esil_is_operator_str() {
+        assert!(Tokenizer::is_operator_str("+="));
+        assert!(Tokenizer::is_operator_str("=[16]"));
+        assert!(!Tokenizer::is_operator_str("eax"));
+        assert!(!Tokenizer::is_operator_str("0x10"));
+    }
+
+    #[test]
+    fn esil_inline_interrupts() {
+        let tokenizer = Tokenizer::new().inline_interrupts(true);
+        assert_eq!(tokenizer.tokenize_with("$3"), vec![Token::EConstant(3), Token::EInterrupt]);
+        assert_eq!(tokenizer.tokenize_with("$0x80"), tokenizer.tokenize_with("0x80,$"));
+        assert_eq!(tokenizer.tokenize_with("$z,$"), vec![Token::IZero(None), Token::EInterrupt]);
+        assert_eq!(Tokenizer::tokenize("$3"), vec![Token::EInvalid]);
+    }
+
+    #[test]
+    fn esil_case_insensitive_keywords() {
+        let tokenizer = Tokenizer::new().case_insensitive_keywords(true);
+        assert_eq!(tokenizer.tokenize_with("eax,dup,Pop,goto"),
+                   vec![Token::EIdentifier("eax".to_owned()),
+                        Token::EDup, Token::EPop, Token::EGoto]);
+        assert_eq!(Tokenizer::tokenize("dup"), vec![Token::EIdentifier("dup".to_owned())]);
+    }
+
+    #[test]
+    fn esil_external_lexer() {
+        // Lexes every word as a register.
+        struct Registers;
+        #[derive(Clone, Debug, PartialEq)]
+        struct Register(String);
+        impl From<Register> for Token {
+            fn from(register: Register) -> Token {
+                Token::EIdentifier(register.0)
+            }
+        }
+        impl Tokenize for Registers {
+            type Token = Register;
+            fn tokenize<T: AsRef<str>>(esil: T) -> Vec<Register> {
+                esil.as_ref().split(',').map(|t| Register(t.to_owned())).collect()
+            }
+        }
+        let tokens = tokenize_as::<Registers, _>("eax,+");
+        assert_eq!(tokens, vec![Token::EIdentifier("eax".to_owned()),
+                                Token::EIdentifier("+".to_owned())]);
+        assert_eq!(tokenize_as::<Tokenizer, _>("eax,+"), Tokenizer::tokenize("eax,+"));
+    }
+
+    #[test]
+    fn esil_digit_separators() {
+        let tokenizer = Tokenizer::new().digit_separators(true);
+        assert_eq!(tokenizer.tokenize_with("0x1234_5678,1_000"),
+                   vec![Token::EConstant(0x1234_5678), Token::EConstant(1000)]);
+        assert_eq!(tokenizer.tokenize_with("r_1"), vec![Token::EIdentifier("r_1".to_owned())]);
+        assert_eq!(Tokenizer::tokenize("0x1234_5678"),
+                   vec![Token::EIdentifier("0x1234_5678".to_owned())]);
+    }
+
+    #[test]
+    fn esil_alias() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.add_alias("MYOP", vec![Token::EDup, Token::EAdd]);
+        assert_eq!(tokenizer.tokenize_with("eax,MYOP"),
+                   vec![Token::EIdentifier("eax".to_owned()), Token::EDup, Token::EAdd]);
+        tokenizer.add_alias("+", vec![Token::ESub]);
+        assert_eq!(tokenizer.tokenize_with("+"), vec![Token::EAdd]);
+    }
+
+    #[test]
+    fn esil_tokenize_append() {
+        let mut tokens = Vec::new();
+        let mut index = Vec::new();
+        let tokenizer = Tokenizer::new();
+        tokenizer.tokenize_append("4,eax,+=", 0x1000, &mut tokens, &mut index);
+        tokenizer.tokenize_append("eax,ebx,=", 0x1003, &mut tokens, &mut index);
+        assert_eq!(index, vec![(0x1000, 0), (0x1003, 6)]);
+        assert_eq!(&tokens[6..], &Tokenizer::tokenize("eax,ebx,=")[..]);
+    }
+
+    #[test]
+    fn esil_split_by_instruction() {
+        let instructions = vec![(0x1000, "4,eax,+="), (0x1003, "eax,ebx,=")];
+        let streams = Tokenizer::new().split_by_instruction(instructions);
+        assert_eq!(streams, vec![(0x1000, Tokenizer::tokenize("4,eax,+=")),
+                                 (0x1003, Tokenizer::tokenize("eax,ebx,="))]);
+    }
+
+    #[test]
+    fn esil_decrement_memory() {
+        let tokens = Tokenizer::tokenize("0x1000,--=[4]");
+        assert_eq!(tokens, vec![Token::EConstant(0x1000),
+                                Token::PCopy(1), Token::EPeek(32),
+                                Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                                Token::PPop(1), Token::ESub,
+                                Token::PPop(1), Token::EPoke(32)]);
+        assert!(::analysis::max_stack_depth(&tokens).is_ok());
+        let tokens = Tokenizer::tokenize("0x1000,++=[4]");
+        assert!(::analysis::max_stack_depth(&tokens).is_ok());
+    }
+
+    #[test]
+    fn esil_compare() {
+        assert_eq!(Token::ECmp.stack_effect(), (2, 0));
+        assert_eq!(Tokenizer::tokenize("ebx,eax,<="),
+                   Tokenizer::tokenize("ebx,eax,>,!"));
+        // `cmp eax, ebx; jz`: the compare leaves nothing for the conditional
+        // but `$z`.
+        let tokens = Tokenizer::tokenize("ebx,eax,==,$z,?{,0x1000,rip,=,}");
+        assert_eq!(::analysis::tokenize_checked("ebx,eax,==,$z,?{,0x1000,rip,=,}"), Ok(tokens));
+    }
+
+    #[test]
+    fn esil_size_var() {
+        assert_eq!(Tokenizer::tokenize("$r"), vec![Token::ISize(None)]);
+        assert_eq!(Tokenizer::tokenize("$r32"), vec![Token::ISize(Some(32))]);
+        assert!(Token::ISize(None).describe().contains("word size"));
+        assert!(Token::ISize(Some(32)).describe().contains("32"));
+    }
+
+    #[test]
+    fn esil_internal_var_suffix() {
+        assert_eq!(Tokenizer::tokenize("$c"), vec![Token::ICarry(None)]);
+        assert_eq!(Tokenizer::tokenize("$c0"), vec![Token::ICarry(Some(0))]);
+        assert_eq!(Tokenizer::tokenize("$c7"), vec![Token::ICarry(Some(7))]);
+    }
 }
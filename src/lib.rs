@@ -2,3 +2,11 @@ extern crate num;
 
 pub mod lexer;
 pub mod parser;
+pub mod analysis;
+pub mod transform;
+pub mod serializer;
+pub mod ssa;
+pub mod builder;
+pub mod eval;
+#[cfg(feature = "ffi")]
+pub mod ffi;
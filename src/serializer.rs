@@ -0,0 +1,186 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::OnceLock;
+
+use lexer::{Token, Tokenize, Tokenizer, OPERATORS};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerializeError {
+    /// The token at this index is not part of any ESIL operator.
+    Unrepresentable(usize),
+}
+
+/// How `to_radare_esil_with` prints constants.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ConstantFormat {
+    /// `0xff`, as radare2 does.
+    #[default]
+    Hex,
+    /// `255`.
+    Decimal,
+}
+
+/// Print `tokens` in radare2's ESIL syntax.
+///
+/// Operator expansions are collapsed back into the operator that produces
+/// them, so the tokens of `+=` print as `+=`. Where operators share an
+/// expansion the canonical spelling from `OPERATORS` is used: `[*]` prints
+/// as `[]`, and `1,+` as `++`. Parser instructions that are not part of an
+/// expansion cannot be printed.
+pub fn to_radare_esil(tokens: &[Token]) -> Result<String, SerializeError> {
+    to_radare_esil_with(tokens, ConstantFormat::Hex)
+}
+
+/// `to_radare_esil`, printing constants in the given format.
+pub fn to_radare_esil_with(tokens: &[Token],
+                           format: ConstantFormat) -> Result<String, SerializeError> {
+    // Memory operators at other widths than the listed ones, eg. `[16]`.
+    let widths = tokens.iter()
+                       .filter_map(|t| match *t {
+                           Token::EPeek(bits) | Token::EPoke(bits) => Some(bits),
+                           _ => None,
+                       })
+                       .filter(|bits| bits % 8 == 0 && ![0, 8, 16, 32, 64].contains(bits))
+                       .collect::<BTreeSet<_>>();
+    let mut sized = Vec::new();
+    for bits in widths {
+        for prefix in OPERATORS.iter().filter_map(|op| op.strip_suffix("[]")) {
+            let op = format!("{}[{}]", prefix, bits / 8);
+            let expansion = Tokenizer::tokenize(&op);
+            sized.push((op, expansion));
+        }
+    }
+    let sized = sized.iter().map(|(op, expansion)| (op.as_str(), expansion));
+    let expansions = expansions().iter().map(|(op, expansion)| (*op, expansion)).chain(sized);
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let mut longest: Option<(&str, usize)> = None;
+        for (op, expansion) in expansions.clone() {
+            if tokens[i..].starts_with(expansion) &&
+               longest.is_none_or(|(_, len)| expansion.len() > len) {
+                longest = Some((op, expansion.len()));
+            }
+        }
+        if let Some((op, len)) = longest {
+            words.push(op.to_owned());
+            i += len;
+        } else {
+            words.push(operand(&tokens[i], format).ok_or(SerializeError::Unrepresentable(i))?);
+            i += 1;
+        }
+    }
+    Ok(words.join(","))
+}
+
+/// One-line, human friendly form of `tokens` for logs and tracing: each
+/// token is printed on its own as its ESIL mnemonic, separated by spaces.
+/// Unlike `to_radare_esil` expansions are not collapsed, and tokens with no
+/// ESIL spelling, such as parser instructions, print as in `Debug`.
+pub fn display_stream(tokens: &[Token]) -> String {
+    let words = tokens.iter().map(|token| {
+        operand(token, ConstantFormat::Hex)
+            .or_else(|| mnemonics().get(token).map(|op| (*op).to_owned()))
+            .or_else(|| match *token {
+                // Widths without an operator of their own, eg. `[16]`.
+                Token::EPeek(bits) if bits % 8 == 0 => Some(format!("[{}]", bits / 8)),
+                Token::EPoke(bits) if bits % 8 == 0 => Some(format!("=[{}]", bits / 8)),
+                _ => None,
+            })
+            .unwrap_or_else(|| format!("{:?}", token))
+    });
+    words.collect::<Vec<_>>().join(" ")
+}
+
+// Every operator with the tokens it expands to, in `OPERATORS` order.
+fn expansions() -> &'static [(&'static str, Vec<Token>)] {
+    static EXPANSIONS: OnceLock<Vec<(&'static str, Vec<Token>)>> = OnceLock::new();
+    EXPANSIONS.get_or_init(|| OPERATORS.iter().map(|op| (*op, Tokenizer::tokenize(op))).collect())
+}
+
+// The canonical operator expanding to each single token.
+fn mnemonics() -> &'static HashMap<Token, &'static str> {
+    static MNEMONICS: OnceLock<HashMap<Token, &'static str>> = OnceLock::new();
+    MNEMONICS.get_or_init(|| {
+        let mut mnemonics = HashMap::new();
+        for (op, expansion) in expansions() {
+            if let [ref token] = expansion[..] {
+                mnemonics.entry(token.clone()).or_insert(*op);
+            }
+        }
+        mnemonics
+    })
+}
+
+fn operand(token: &Token, format: ConstantFormat) -> Option<String> {
+    let (var, bit) = match *token {
+        Token::EConstant(v) => {
+            return Some(match format {
+                ConstantFormat::Hex => format!("0x{:x}", v),
+                ConstantFormat::Decimal => v.to_string(),
+            });
+        }
+        Token::EIdentifier(ref name) => return Some(name.clone()),
+        Token::IZero(bit) => ('z', bit),
+        Token::ICarry(bit) => ('c', bit),
+        Token::IParity(bit) => ('p', bit),
+        Token::IOverflow(bit) => ('o', bit),
+        Token::ISign(bit) => ('s', bit),
+        Token::IBorrow(bit) => ('b', bit),
+        Token::ISize(bit) => ('r', bit),
+        Token::IAddress(bit) => ('$', bit),
+        _ => return None,
+    };
+    Some(match bit {
+        Some(bit) => format!("${}{}", var, bit),
+        None => format!("${}", var),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(esil: &str) -> String {
+        to_radare_esil(&Tokenizer::tokenize(esil)).unwrap()
+    }
+
+    #[test]
+    fn compound_operators() {
+        assert_eq!(round_trip("ebx,eax,+="), "ebx,eax,+=");
+        assert_eq!(round_trip("eax,++"), "eax,++");
+        assert_eq!(round_trip("ebx,eax,L*"), "ebx,eax,L*");
+        assert_eq!(round_trip("0x1,0x1000,--=[4]"), "0x1,0x1000,--=[4]");
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(round_trip("$z,$c7,$$,?{,0x10,eax,=,}"), "$z,$c7,$$,?{,0x10,eax,=,}");
+        assert_eq!(round_trip("0x1000,[*]"), "0x1000,[]");
+        assert_eq!(round_trip("xmm0,rsp,=[16],rsp,[32]"), "xmm0,rsp,=[16],rsp,[32]");
+        assert_eq!(round_trip("0x1,rsp,+=[16]"), "0x1,rsp,+=[16]");
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(display_stream(&Tokenizer::tokenize("4,eax,+")), "0x4 eax +");
+        assert_eq!(display_stream(&Tokenizer::tokenize("eax,ebx,+=")),
+                   "eax ebx PCopy(1) + PPop(1) =");
+        assert_eq!(display_stream(&Tokenizer::tokenize("eax,[1]")), "eax [1]");
+        assert_eq!(display_stream(&Tokenizer::tokenize("eax,[16],ebx,=[3]")),
+                   "eax [16] ebx =[3]");
+    }
+
+    #[test]
+    fn constant_format() {
+        let tokens = [Token::EConstant(255), Token::EIdentifier("eax".to_owned()), Token::EEq];
+        assert_eq!(to_radare_esil(&tokens).unwrap(), "0xff,eax,=");
+        assert_eq!(to_radare_esil_with(&tokens, ConstantFormat::Hex).unwrap(), "0xff,eax,=");
+        assert_eq!(to_radare_esil_with(&tokens, ConstantFormat::Decimal).unwrap(), "255,eax,=");
+    }
+
+    #[test]
+    fn unrepresentable() {
+        let tokens = [Token::EIdentifier("eax".to_owned()), Token::PPop(1)];
+        assert_eq!(to_radare_esil(&tokens), Err(SerializeError::Unrepresentable(1)));
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeSet, HashMap};
+use std::slice;
+
+use analysis::StackError;
+use lexer::Token;
+use serializer::display_stream;
+
+/// A token with the implicit stack made explicit: each value pushed gets a
+/// unique id, and each operation names the ids it consumes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsaOp {
+    /// Id of the value this operation pushes, if any. An operation pushing
+    /// several values, such as `L*`, pushes consecutive ids from this one.
+    pub result: Option<usize>,
+    pub op: Token,
+    /// Ids of the consumed values, in the order they were pushed. As in
+    /// ESIL, the last one is the operator's first operand.
+    pub operands: Vec<usize>,
+}
+
+/// Number the values of the ESIL stack in `tokens` and turn every operation
+/// into an `SsaOp`.
+///
+/// Tokens that only shuffle existing values (`DUP`, `CLEAR` and the parser
+/// instructions) produce no operation; they move ids around instead.
+/// Conditional blocks are treated as taken.
+pub fn to_ssa(tokens: &[Token]) -> Result<Vec<SsaOp>, StackError> {
+    let mut ops = Vec::new();
+    let mut stack: Vec<usize> = Vec::new();
+    let mut temp: Vec<usize> = Vec::new();
+    let mut next = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let (pop, push) = token.stack_effect();
+        if stack.len() < pop {
+            return Err(StackError::Underflow(i));
+        }
+        match *token {
+            Token::EDup => {
+                let top = stack[stack.len() - 1];
+                stack.push(top);
+            }
+            Token::EClear => stack.clear(),
+            Token::PCopy(n) => {
+                let from = stack.len() - n;
+                temp.extend_from_slice(&stack[from..]);
+            }
+            Token::PPop(n) => {
+                if temp.len() < n {
+                    return Err(StackError::Underflow(i));
+                }
+                let from = temp.len() - n;
+                stack.extend(temp.drain(from..));
+            }
+            Token::PSync => { }
+            _ => {
+                let from = stack.len() - pop;
+                let operands = stack.split_off(from);
+                let result = if push > 0 { Some(next) } else { None };
+                stack.extend(next..next + push);
+                next += push;
+                ops.push(SsaOp {
+                    result,
+                    op: token.clone(),
+                    operands,
+                });
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// Graphviz DOT of the expression trees in `ops`. Each operation is a node
+/// labelled with its mnemonic, with an edge to the operation producing each
+/// of its operands. Operands produced outside `ops`, as when it is part of
+/// a longer stream, are input nodes labelled with their id.
+pub fn to_dot(ops: &[SsaOp]) -> String {
+    let mut producers = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        if let Some(first) = op.result {
+            producers.extend((first..first + op.op.stack_effect().1).map(|v| (v, i)));
+        }
+    }
+    let mut inputs = BTreeSet::new();
+    let mut dot = String::from("digraph esil {\n");
+    for (i, op) in ops.iter().enumerate() {
+        let label = display_stream(slice::from_ref(&op.op)).replace('"', "\\\"");
+        dot.push_str(&format!("    n{} [label=\"{}\"];\n", i, label));
+        for operand in &op.operands {
+            match producers.get(operand) {
+                Some(producer) => dot.push_str(&format!("    n{} -> n{};\n", i, producer)),
+                None => {
+                    inputs.insert(*operand);
+                    dot.push_str(&format!("    n{} -> v{};\n", i, operand));
+                }
+            }
+        }
+    }
+    for input in inputs {
+        dot.push_str(&format!("    v{} [label=\"v{}\", shape=box];\n", input, input));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::{Tokenize, Tokenizer};
+
+    #[test]
+    fn ssa_add() {
+        let ops = to_ssa(&Tokenizer::tokenize("4,5,+")).unwrap();
+        // v2 = v0 + v1
+        assert_eq!(ops[2], SsaOp {
+            result: Some(2),
+            op: Token::EAdd,
+            operands: vec![0, 1],
+        });
+        assert_eq!(ops.len(), 3);
+    }
+
+    #[test]
+    fn dot() {
+        let dot = to_dot(&to_ssa(&Tokenizer::tokenize("4,5,+")).unwrap());
+        assert!(dot.contains("n2 [label=\"+\"];"));
+        assert!(dot.contains("n2 -> n0;") && dot.contains("n2 -> n1;"));
+        assert!(dot.contains("n0 [label=\"0x4\"];"));
+        // The operands of `+` come from outside the slice.
+        let dot = to_dot(&to_ssa(&Tokenizer::tokenize("4,5,+")).unwrap()[2..]);
+        assert!(dot.contains("n0 [label=\"+\"];"));
+        assert!(dot.contains("v0 [label=\"v0\", shape=box];") && dot.contains("n0 -> v1;"));
+    }
+
+    #[test]
+    fn ssa_compound_assignment() {
+        // `eax,+=` copies eax through the temporary stack as the destination.
+        let ops = to_ssa(&Tokenizer::tokenize("1,eax,+=")).unwrap();
+        assert_eq!(ops.iter().map(|o| o.operands.clone()).collect::<Vec<_>>(),
+                   vec![vec![], vec![], vec![0, 1], vec![2, 1]]);
+        assert_eq!(ops[3].op, Token::EEq);
+        assert_eq!(to_ssa(&Tokenizer::tokenize("+")), Err(StackError::Underflow(0)));
+    }
+
+    #[test]
+    fn ssa_long_mul() {
+        // Both halves of the product get an id.
+        let ops = to_ssa(&Tokenizer::tokenize("4,5,L*,+")).unwrap();
+        assert_eq!(ops[2].result, Some(2));
+        assert_eq!(ops[3], SsaOp {
+            result: Some(4),
+            op: Token::EAdd,
+            operands: vec![2, 3],
+        });
+        assert!(to_dot(&ops).contains("n3 -> n2;"));
+    }
+}
@@ -0,0 +1,434 @@
+use lexer::{Token, TokenKind};
+
+/// Rewrite the legacy increment and decrement expansions of `++`, `--` and
+/// `--=` (eg. `PPop(1), 1, +` for `++`) into the explicit `1,+` / `1,-`
+/// arithmetic the lexer emits.
+///
+/// A decrement keeps the value being decremented on top of the stack, so
+/// the `1` is slipped underneath it through the temporary stack.
+///
+/// The legacy `PPop(1)` of `++` and `--` pops nothing, so it is only
+/// rewritten while the temporary stack is empty; otherwise it belongs to an
+/// earlier `PCopy`.
+pub fn normalize_increments(tokens: &[Token]) -> Vec<Token> {
+    let decrement = [Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                     Token::PPop(1), Token::ESub];
+    let mut result = Vec::with_capacity(tokens.len());
+    // Values held on the temporary stack.
+    let mut temp = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let rest = &tokens[i..];
+        if temp == 0 && rest.starts_with(&[Token::PPop(1), Token::EConstant(1), Token::EAdd]) {
+            // `++`
+            result.extend_from_slice(&[Token::EConstant(1), Token::EAdd]);
+            i += 3;
+        } else if temp == 0 &&
+                  rest.starts_with(&[Token::PPop(1), Token::EConstant(1), Token::ESub]) {
+            // `--`
+            result.extend_from_slice(&decrement);
+            i += 3;
+        } else if rest.starts_with(&[Token::PCopy(1), Token::EConstant(1), Token::ESub,
+                                     Token::PPop(1), Token::EEq]) {
+            // `--=`
+            result.push(Token::PCopy(1));
+            result.extend_from_slice(&decrement);
+            result.extend_from_slice(&[Token::PPop(1), Token::EEq]);
+            i += 5;
+        } else {
+            match tokens[i] {
+                Token::PCopy(n) => temp += n,
+                Token::PPop(n) => temp = temp.saturating_sub(n),
+                _ => {}
+            }
+            result.push(tokens[i].clone());
+            i += 1;
+        }
+    }
+    result
+}
+
+/// Join the token streams of several instructions into one, separated by
+/// `CLEAR` so no instruction sees values left on the stack by the previous
+/// one.
+pub fn concat_instructions(streams: &[Vec<Token>]) -> Vec<Token> {
+    let mut result = Vec::new();
+    for (i, stream) in streams.iter().enumerate() {
+        if i > 0 {
+            result.push(Token::EClear);
+        }
+        result.extend_from_slice(stream);
+    }
+    result
+}
+
+/// Put the operands of commutative operators in a fixed order, so that eg.
+/// `4,eax,+` and `eax,4,+` produce the same tokens. Only operators applied
+/// directly to two operand tokens are reordered.
+pub fn canonicalize_commutative(tokens: &[Token]) -> Vec<Token> {
+    let mut result = tokens.to_vec();
+    for i in 2..result.len() {
+        if result[i].is_commutative() && result[i - 2].is_operand() &&
+           result[i - 1].is_operand() && result[i - 2] > result[i - 1] {
+            result.swap(i - 2, i - 1);
+        }
+    }
+    result
+}
+
+/// Put every expression built from operands and pure operators in a fixed
+/// form: chains of the same commutative operator are flattened and their
+/// operands sorted, recursively. `a,b,+,c,+` and `c,a,+,b,+` thus produce
+/// the same tokens, as do `4,eax,*,ebx,+` and `ebx,eax,4,*,+`.
+///
+/// Only the order in which the operands are combined changes, so flags set
+/// by the reordered operators may differ, as with `canonicalize_commutative`.
+/// Tokens with side effects, the parser instructions and the flags, which
+/// read what the operators before them set, stay where they are.
+pub fn canonicalize_expressions(tokens: &[Token]) -> Vec<Token> {
+    let mut result = Vec::with_capacity(tokens.len());
+    // Values on the stack, `None` once their tokens are in `result`. Values
+    // still held as expressions are always above those.
+    let mut stack: Vec<Option<Expr>> = Vec::new();
+    for token in tokens {
+        let (pop, push) = token.stack_effect();
+        let pure = match token.kind() {
+            TokenKind::Arithmetic | TokenKind::Logic | TokenKind::Comparison |
+            TokenKind::Constant | TokenKind::Identifier => true,
+            TokenKind::InternalVar => matches!(*token, Token::ISize(_) | Token::IAddress(_)),
+            TokenKind::Memory => pop == 1,
+            _ => false,
+        };
+        let from = stack.len().saturating_sub(pop);
+        if pure && push == 1 && stack.len() >= pop && stack[from..].iter().all(Option::is_some) {
+            let operands = stack.drain(from..).map(Option::unwrap).collect();
+            stack.push(Some(Expr::new(token, operands)));
+            continue;
+        }
+        for value in &mut stack {
+            if let Some(expr) = value.take() {
+                expr.emit(&mut result);
+            }
+        }
+        result.push(token.clone());
+        if *token == Token::EClear {
+            stack.clear();
+        } else {
+            stack.truncate(from);
+            stack.extend((0..push).map(|_| None));
+        }
+    }
+    for expr in stack.into_iter().flatten() {
+        expr.emit(&mut result);
+    }
+    result
+}
+
+// Expression tree used by `canonicalize_expressions`.
+enum Expr {
+    Leaf(Token),
+    // Operands in the order they are pushed. A commutative operator has
+    // any number of operands, combined left to right.
+    Node(Token, Vec<Expr>),
+}
+
+impl Expr {
+    fn new(op: &Token, operands: Vec<Expr>) -> Expr {
+        if operands.is_empty() {
+            return Expr::Leaf(op.clone());
+        }
+        if !op.is_commutative() {
+            return Expr::Node(op.clone(), operands);
+        }
+        let mut flat = Vec::new();
+        for operand in operands {
+            match operand {
+                Expr::Node(inner, children) if inner == *op => flat.extend(children),
+                operand => flat.push(operand),
+            }
+        }
+        flat.sort_by_cached_key(Expr::tokens);
+        Expr::Node(op.clone(), flat)
+    }
+
+    fn emit(&self, out: &mut Vec<Token>) {
+        match *self {
+            Expr::Leaf(ref token) => out.push(token.clone()),
+            Expr::Node(ref op, ref operands) if op.is_commutative() => {
+                for (i, operand) in operands.iter().enumerate() {
+                    operand.emit(out);
+                    if i > 0 {
+                        out.push(op.clone());
+                    }
+                }
+            }
+            Expr::Node(ref op, ref operands) => {
+                for operand in operands {
+                    operand.emit(out);
+                }
+                out.push(op.clone());
+            }
+        }
+    }
+
+    fn tokens(&self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        self.emit(&mut tokens);
+        tokens
+    }
+}
+
+/// Merge pairs of constant stores of the same width to consecutive
+/// constant addresses, eg. `0x12,0x1000,=[1],0x34,0x1001,=[1]` into
+/// `0x3412,0x1000,=[2]`, repeatedly up to 64 bits. Memory is assumed to be
+/// little-endian. Word-sized stores are left alone; see `normalize_widths`.
+pub fn coalesce_stores(tokens: &[Token]) -> Vec<Token> {
+    let mut result = tokens.to_vec();
+    let mut i = 0;
+    while i + 6 <= result.len() {
+        let merged = match result[i..i + 6] {
+            [Token::EConstant(v1), Token::EConstant(a1), Token::EPoke(w1),
+             Token::EConstant(v2), Token::EConstant(a2), Token::EPoke(w2)]
+                if w1 == w2 && w1 % 8 == 0 && (1..=32).contains(&w1) &&
+                   a1.checked_add(u64::from(w1 / 8)) == Some(a2) => {
+                let mask = (1 << w1) - 1;
+                Some([Token::EConstant((v1 & mask) | ((v2 & mask) << w1)),
+                      Token::EConstant(a1),
+                      Token::EPoke(w1 * 2)])
+            }
+            _ => None,
+        };
+        match merged {
+            Some(store) => {
+                result.splice(i..i + 6, store.iter().cloned());
+                // The wider store may merge with the one before it.
+                i = i.saturating_sub(3);
+            }
+            None => i += 1,
+        }
+    }
+    result
+}
+
+/// Give every token whose width depends on the target an explicit one:
+/// the unsized memory operators (`[]`, `=[]`, `+=[]`...) and `$r` are
+/// rewritten for a word of `word_size` bits, eg. `[]` becomes `[4]` for a
+/// 32-bit target. This makes streams comparable with radare2's output for
+/// that target.
+pub fn normalize_widths(tokens: &mut [Token], word_size: u8) {
+    for token in tokens {
+        match *token {
+            Token::EPeek(ref mut bits) | Token::EPoke(ref mut bits) if *bits == 0 => {
+                *bits = u16::from(word_size);
+            }
+            Token::ISize(ref mut bits @ None) => *bits = Some(word_size),
+            _ => {}
+        }
+    }
+}
+
+/// Remove the parser instructions (`PCopy`, `PPop`, `PSync`) from a stream.
+pub fn strip_parser_instructions(tokens: &[Token]) -> Vec<Token> {
+    tokens.iter()
+          .filter(|t| !matches!(**t, Token::PCopy(_) | Token::PPop(_) | Token::PSync))
+          .cloned()
+          .collect()
+}
+
+/// Simplify parser instructions where that provably leaves both the ESIL
+/// stack and the temporary stack as they were:
+///
+/// * `PCopy(1), PPop(1)` only duplicates the top of the stack, and becomes
+///   `DUP`.
+/// * A `PCopy` with no `PPop` anywhere after it fills the temporary stack
+///   for nothing, and is dropped.
+///
+/// Anything else is left alone.
+pub fn optimize_parser_ops(tokens: &[Token]) -> Vec<Token> {
+    let last_pop = tokens.iter().rposition(|t| matches!(*t, Token::PPop(_)));
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            Token::PCopy(1) if tokens.get(i + 1) == Some(&Token::PPop(1)) => {
+                result.push(Token::EDup);
+                i += 1;
+            }
+            Token::PCopy(_) if last_pop.is_none_or(|p| p < i) => { }
+            ref token => result.push(token.clone()),
+        }
+        i += 1;
+    }
+    result
+}
+
+/// Replace `$z` with a constant when the comparison that sets it has
+/// constant operands, eg. `0,0,==,$z` becomes `0,0,==,1`.
+///
+/// Any token that is not an operand, a parser instruction or `:=` may update
+/// the flags, so the known value is forgotten after it.
+pub fn propagate_known_flags(tokens: &[Token]) -> Vec<Token> {
+    let mut result: Vec<Token> = Vec::with_capacity(tokens.len());
+    // Result of the last comparison, when known.
+    let mut known = None;
+    for token in tokens {
+        let len = result.len();
+        match *token {
+            Token::ECmp if len >= 2 => {
+                known = match (result[len - 1].as_constant(), result[len - 2].as_constant()) {
+                    (Some(a), Some(b)) => Some(a.wrapping_sub(b)),
+                    _ => None,
+                };
+            }
+            Token::IZero(None) => {
+                if let Some(v) = known {
+                    result.push(Token::EConstant((v == 0) as u64));
+                    continue;
+                }
+            }
+            Token::PCopy(_) | Token::PPop(_) | Token::PSync | Token::EWeakEq => { }
+            _ if token.is_operand() => { }
+            _ => known = None,
+        }
+        result.push(token.clone());
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use analysis::max_stack_depth;
+    use lexer::{Tokenize, Tokenizer};
+    use ssa::to_ssa;
+
+    #[test]
+    fn normalize_legacy_increment() {
+        let tokens = [Token::EIdentifier("eax".to_owned()),
+                      Token::PPop(1), Token::EConstant(1), Token::EAdd];
+        assert_eq!(normalize_increments(&tokens), Tokenizer::tokenize("eax,1,+"));
+        let tokens = [Token::EIdentifier("eax".to_owned()),
+                      Token::PPop(1), Token::EConstant(1), Token::ESub];
+        assert_eq!(normalize_increments(&tokens), Tokenizer::tokenize("eax,--"));
+        // This `PPop` restores the value copied by the `PCopy`.
+        let tokens = [Token::EIdentifier("a".to_owned()), Token::PCopy(1),
+                      Token::PPop(1), Token::EConstant(1), Token::EAdd];
+        assert_eq!(normalize_increments(&tokens), tokens);
+    }
+
+    #[test]
+    fn normalize_increment() {
+        let tokens = normalize_increments(&Tokenizer::tokenize("eax,++"));
+        assert_eq!(tokens, Tokenizer::tokenize("eax,1,+"));
+        let tokens = normalize_increments(&Tokenizer::tokenize("eax,++="));
+        assert_eq!(tokens, Tokenizer::tokenize("eax,++="));
+    }
+
+    #[test]
+    fn canonical_operands() {
+        assert_eq!(canonicalize_commutative(&Tokenizer::tokenize("4,eax,+")),
+                   canonicalize_commutative(&Tokenizer::tokenize("eax,4,+")));
+        assert!(canonicalize_commutative(&Tokenizer::tokenize("4,eax,-")) !=
+                canonicalize_commutative(&Tokenizer::tokenize("eax,4,-")));
+    }
+
+    #[test]
+    fn optimize_parser() {
+        let eax = Token::EIdentifier("eax".to_owned());
+        let tokens = [eax.clone(), Token::PCopy(1), Token::PPop(1), Token::EAdd,
+                      Token::PCopy(1), Token::EConstant(4), Token::EAdd];
+        let optimized = optimize_parser_ops(&tokens);
+        assert_eq!(optimized, vec![eax, Token::EDup, Token::EAdd,
+                                   Token::EConstant(4), Token::EAdd]);
+        assert_eq!(to_ssa(&optimized), to_ssa(&tokens));
+        assert_eq!(max_stack_depth(&optimized), max_stack_depth(&tokens));
+        // Every compound operator restores what it copies.
+        let tokens = Tokenizer::tokenize("4,eax,+=,0x1000,--=[4]");
+        assert_eq!(optimize_parser_ops(&tokens), tokens);
+    }
+
+    #[test]
+    fn known_flags() {
+        assert_eq!(propagate_known_flags(&Tokenizer::tokenize("0,0,==,$z,zf,=")),
+                   Tokenizer::tokenize("0,0,==,1,zf,="));
+        assert_eq!(propagate_known_flags(&Tokenizer::tokenize("1,0,==,$z")),
+                   Tokenizer::tokenize("1,0,==,0"));
+        let tokens = Tokenizer::tokenize("0,0,==,eax,ebx,=,$z");
+        assert_eq!(propagate_known_flags(&tokens), tokens);
+        // Unlike `=`, `:=` leaves the flags of the comparison.
+        assert_eq!(propagate_known_flags(&Tokenizer::tokenize("0,0,==,eax,ebx,:=,$z")),
+                   Tokenizer::tokenize("0,0,==,eax,ebx,:=,1"));
+        let tokens = Tokenizer::tokenize("eax,0,==,$z");
+        assert_eq!(propagate_known_flags(&tokens), tokens);
+    }
+
+    #[test]
+    fn canonical_expressions() {
+        let canonical = |esil| canonicalize_expressions(&Tokenizer::tokenize(esil));
+        assert_eq!(canonical("1,2,+,3,+"), canonical("3,1,+,2,+"));
+        assert_eq!(canonical("1,2,+,3,+"), Tokenizer::tokenize("1,2,+,3,+"));
+        assert_eq!(canonical("4,eax,*,ebx,+"), canonical("ebx,eax,4,*,+"));
+        assert!(canonical("4,eax,-,ebx,+") != canonical("eax,4,-,ebx,+"));
+        // Side effects keep their place.
+        assert_eq!(canonical("eax,4,+,ecx,=,ebx,2,+,edx,="),
+                   Tokenizer::tokenize("4,eax,+,ecx,=,2,ebx,+,edx,="));
+        let tokens = Tokenizer::tokenize("4,eax,+=");
+        assert_eq!(canonicalize_expressions(&tokens), tokens);
+        // `$z` is read after the `+` that sets it.
+        let tokens = Tokenizer::tokenize("eax,ebx,+,$z,|");
+        assert_eq!(canonicalize_expressions(&tokens), tokens);
+        assert_eq!(canonical("$$,4,+"), canonical("4,$$,+"));
+    }
+
+    #[test]
+    fn coalesce() {
+        let tokens = Tokenizer::tokenize("0x12,0x1000,=[1],0x34,0x1001,=[1]");
+        assert_eq!(coalesce_stores(&tokens), Tokenizer::tokenize("0x3412,0x1000,=[2]"));
+        let tokens = Tokenizer::tokenize("1,0x10,=[1],2,0x11,=[1],3,0x12,=[1],4,0x13,=[1]");
+        assert_eq!(coalesce_stores(&tokens), Tokenizer::tokenize("0x4030201,0x10,=[4]"));
+        let tokens = Tokenizer::tokenize("1,0x10,=[1],2,0x12,=[1],eax,0x13,=[1]");
+        assert_eq!(coalesce_stores(&tokens), tokens);
+        // Two 5-byte stores would not fit in a constant.
+        let tokens = Tokenizer::tokenize("0xffffffffff,0x1000,=[5],0xffffffffff,0x1005,=[5]");
+        assert_eq!(coalesce_stores(&tokens), tokens);
+        // Stores of less than a byte.
+        let tokens = [Token::EConstant(1), Token::EConstant(0x10), Token::EPoke(4),
+                      Token::EConstant(2), Token::EConstant(0x10), Token::EPoke(4)];
+        assert_eq!(coalesce_stores(&tokens), tokens);
+    }
+
+    #[test]
+    fn normalize_word_size() {
+        let mut tokens = Tokenizer::tokenize("eax,[],$r,ebx,+=[]");
+        normalize_widths(&mut tokens, 32);
+        assert_eq!(tokens, Tokenizer::tokenize("eax,[4],$r32,ebx,+=[4]"));
+        let mut tokens = Tokenizer::tokenize("eax,[2]");
+        normalize_widths(&mut tokens, 64);
+        assert_eq!(tokens, Tokenizer::tokenize("eax,[2]"));
+    }
+
+    #[test]
+    fn strip_parser() {
+        assert_eq!(strip_parser_instructions(&Tokenizer::tokenize("ebx,eax,+=")),
+                   Tokenizer::tokenize("ebx,eax,+,="));
+    }
+
+    #[test]
+    fn concat() {
+        let streams = [Tokenizer::tokenize("4,eax,="), Tokenizer::tokenize("eax,ebx,=")];
+        let mut expected = Tokenizer::tokenize("4,eax,=,CLEAR,eax,ebx,=");
+        assert_eq!(concat_instructions(&streams), expected);
+        expected.truncate(3);
+        assert_eq!(concat_instructions(&streams[..1]), expected);
+    }
+
+    #[test]
+    fn normalize_decrement() {
+        let tokens = normalize_increments(&Tokenizer::tokenize("eax,--="));
+        assert_eq!(tokens, vec![Token::EIdentifier("eax".to_owned()),
+                                Token::PCopy(1),
+                                Token::PCopy(1), Token::EPop, Token::EConstant(1),
+                                Token::PPop(1), Token::ESub,
+                                Token::PPop(1), Token::EEq]);
+    }
+}